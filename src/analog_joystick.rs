@@ -0,0 +1,115 @@
+//! This module reads an analog thumbstick through the ADC
+//!
+//! Some cabinets use an analog thumbstick instead of the 4-way arcade joystick. The two
+//! potentiometers of the stick are sampled on A0 (x-axis) and A1 (y-axis), and the deflection is
+//! turned into proportional speed commands for the gantry axes.
+//!
+//! The ADC has no interrupt driven abstraction in the HAL, thus the task samples the stick every
+//! SAMPLE_INTERVAL_MS. The rest position of the stick is sampled once when the task starts, and
+//! deflections within the dead zone around it are ignored, so a worn stick does not drift.
+//!
+//! The analog thumbstick is selected with the `analog-joystick` cargo feature.
+
+use arduino_hal::hal::port::{PF0, PF1};
+use arduino_hal::port::mode::Analog;
+use arduino_hal::port::Pin;
+use arduino_hal::Adc;
+
+use crate::channel::Sender;
+use crate::stepper::StepperDirection::{ClockWise, CounterClockWise, Idle};
+use crate::stepper::{StepperCommand, FULL_SPEED};
+use crate::timer::delay_ms;
+
+/// interval between two samples of the stick
+const SAMPLE_INTERVAL_MS: u16 = 20;
+
+/// number of distinct speeds, every new speed interrupts the current step so the noise of the ADC
+/// must not result in a new command every sample
+const SPEED_LEVELS: u32 = 8;
+
+/**
+Calibration of one axis of the stick in raw ADC values
+min => reading at full deflection towards the negative direction
+max => reading at full deflection towards the positive direction
+dead zone => deflection around the center that is ignored
+*/
+#[derive(Clone, Copy)]
+pub struct AnalogCalibration {
+    pub min: u16,
+    pub max: u16,
+    pub dead_zone: u16,
+}
+
+impl Default for AnalogCalibration {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: 1023,
+            dead_zone: 40,
+        }
+    }
+}
+
+impl AnalogCalibration {
+    /**
+    Converts a reading into a command for the axis
+
+    Positive deflections move the axis counter-clockwise like pushing the digital joystick right or
+    forward
+    */
+    fn command(&self, center: u16, reading: u16) -> StepperCommand {
+        let reading = reading.clamp(self.min, self.max);
+        let (direction, deflection, range) = if reading > center {
+            (
+                CounterClockWise,
+                reading - center,
+                self.max.saturating_sub(center),
+            )
+        } else {
+            (ClockWise, center - reading, center.saturating_sub(self.min))
+        };
+
+        if deflection <= self.dead_zone || range <= self.dead_zone {
+            return StepperCommand::new(Idle, 0);
+        }
+
+        // the lowest level starts at the edge of the dead zone
+        let deflection = (deflection - self.dead_zone) as u32;
+        let range = (range - self.dead_zone) as u32;
+        let level = ((deflection * SPEED_LEVELS + range - 1) / range).min(SPEED_LEVELS);
+        StepperCommand::new(direction, (level * FULL_SPEED as u32 / SPEED_LEVELS) as u8)
+    }
+}
+
+/**
+Task that samples the analog stick and drives both gantry axes
+*/
+pub async fn analog_joystick_task(
+    adc: &mut Adc,
+    x_pin: &Pin<Analog, PF0>,
+    y_pin: &Pin<Analog, PF1>,
+    calibration: AnalogCalibration,
+    x_sender: Sender<'_, StepperCommand>,
+    y_sender: Sender<'_, StepperCommand>,
+) {
+    // the stick is expected to rest when the game starts
+    let x_center = x_pin.analog_read(adc);
+    let y_center = y_pin.analog_read(adc);
+
+    let mut x_command = StepperCommand::IDLE;
+    let mut y_command = StepperCommand::IDLE;
+    loop {
+        delay_ms(SAMPLE_INTERVAL_MS).await;
+
+        let new_x_command = calibration.command(x_center, x_pin.analog_read(adc));
+        let new_y_command = calibration.command(y_center, y_pin.analog_read(adc));
+        if new_x_command != x_command {
+            x_sender.send(new_x_command);
+            x_command = new_x_command;
+        }
+        if new_y_command != y_command {
+            y_sender.send(new_y_command);
+            y_command = new_y_command;
+        }
+    }
+}
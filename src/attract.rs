@@ -0,0 +1,209 @@
+//! This module holds the attract mode of the claw machine
+//!
+//! Once nobody has played for the idle period, the machine tries to attract players with light
+//! patterns on the button LEDs and, if enabled, slow tours of the gantry across the field. A coin
+//! or a press of the start button ends the attract mode at once, the gantry is homed afterwards
+//! since a tour may have left it anywhere.
+//!
+//! A coin restarts the idle period, so a player that is about to play is left alone.
+//!
+//! How to use:
+//! Run the idle timeout task while idle, once it times out run the attract tasks until the player
+//! task breaks the executor loop
+
+use arduino_hal::hal::port::Dynamic;
+use arduino_hal::port::mode::Output;
+use arduino_hal::port::Pin;
+use core::future::join;
+use core::pin::pin;
+use futures::select_biased;
+use futures::FutureExt;
+
+use crate::button::Button;
+use crate::credit;
+use crate::executor::wake_task;
+use crate::settings;
+use crate::stepper::Axis;
+use crate::timer::{delay_ms, delay_s, s_to_generic_ticks, GenericTicker};
+
+/// half of the period of one step during a tour, slower than any move of the player
+const TOUR_HALF_PERIOD_US: u16 = 3000;
+
+/// time the gantry rests at every waypoint of a tour
+const TOUR_PAUSE_S: u8 = 2;
+
+/// time every light pattern runs before the next one follows
+const PATTERN_S: u8 = 10;
+
+/**
+Settings of the attract mode
+idle period => seconds without a game before the attract mode starts
+gantry tours => the gantry tours the field in addition to the light patterns
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AttractConfig {
+    pub idle_period_s: u8,
+    pub gantry_tours: bool,
+}
+
+impl Default for AttractConfig {
+    fn default() -> Self {
+        Self {
+            idle_period_s: 60,
+            gantry_tours: false,
+        }
+    }
+}
+
+/**
+All light patterns of the attract mode, they run one after another
+alternate => the LEDs blink in turns
+together => both LEDs blink at once
+flash => both LEDs flash twice and rest
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LedPattern {
+    Alternate,
+    Together,
+    Flash,
+}
+
+/// all light patterns in the order they run
+const PATTERNS: [LedPattern; 3] = [
+    LedPattern::Alternate,
+    LedPattern::Together,
+    LedPattern::Flash,
+];
+
+impl LedPattern {
+    /**
+    Runs a single cycle of the pattern, every cycle takes one second
+    */
+    async fn cycle(
+        &self,
+        start_led: &mut Pin<Output, Dynamic>,
+        end_led: &mut Pin<Output, Dynamic>,
+    ) {
+        match self {
+            LedPattern::Alternate => {
+                start_led.set_high();
+                end_led.set_low();
+                delay_ms(500).await;
+                start_led.set_low();
+                end_led.set_high();
+                delay_ms(500).await;
+            }
+            LedPattern::Together => {
+                start_led.set_high();
+                end_led.set_high();
+                delay_ms(500).await;
+                start_led.set_low();
+                end_led.set_low();
+                delay_ms(500).await;
+            }
+            LedPattern::Flash => {
+                for _ in 0..2 {
+                    start_led.set_high();
+                    end_led.set_high();
+                    delay_ms(100).await;
+                    start_led.set_low();
+                    end_led.set_low();
+                    delay_ms(100).await;
+                }
+                delay_ms(600).await;
+            }
+        }
+    }
+}
+
+/**
+Task that waits for the idle period and breaks the executor loop once it is over
+
+Every coin restarts the idle period, it only moves the deadline while the running timer is kept.
+Once the timer ends before the deadline, a new timer waits for the remaining time.
+*/
+pub async fn idle_timeout_task(timed_out: &mut bool) {
+    let idle_period = s_to_generic_ticks(settings::current().attract.idle_period_s);
+    let one_second = s_to_generic_ticks(1);
+    let mut deadline = GenericTicker::now() + idle_period;
+    loop {
+        let remaining = deadline.saturating_sub(GenericTicker::now());
+        if remaining == 0 {
+            break;
+        }
+
+        // the remaining time is rounded up, so the timer does not end before the deadline
+        let mut timer = pin!(delay_s(((remaining + one_second - 1) / one_second) as u8).fuse());
+        loop {
+            select_biased! {
+                _ = credit::wait_for_coin().fuse() => {
+                    deadline = GenericTicker::now() + idle_period;
+                },
+                _ = timer.as_mut() => break,
+            }
+        }
+    }
+
+    *timed_out = true;
+    // break the executor loop to advance to attract state
+    wake_task(0xFFFF);
+}
+
+/**
+Task that runs the light patterns one after another
+*/
+pub async fn led_pattern_task(
+    start_led: &mut Pin<Output, Dynamic>,
+    end_led: &mut Pin<Output, Dynamic>,
+) {
+    loop {
+        for pattern in PATTERNS {
+            for _ in 0..PATTERN_S {
+                pattern.cycle(start_led, end_led).await;
+            }
+        }
+    }
+}
+
+/**
+Task that tours the gantry across the field, it does nothing if the tours are disabled
+*/
+pub async fn gantry_tour_task(x_axis: &mut Axis, y_axis: &mut Axis) {
+    if !settings::current().attract.gantry_tours {
+        return;
+    }
+
+    // the waypoints of a tour, in steps from home
+    let (max_x, max_y) = (x_axis.max_steps(), y_axis.max_steps());
+    let tour = [
+        (max_x / 2, max_y / 2),
+        (max_x, 0),
+        (max_x, max_y),
+        (0, max_y),
+        (0, 0),
+    ];
+
+    loop {
+        for (x, y) in tour {
+            join!(
+                x_axis.move_to(x, TOUR_HALF_PERIOD_US),
+                y_axis.move_to(y, TOUR_HALF_PERIOD_US)
+            )
+            .await;
+            delay_s(TOUR_PAUSE_S).await;
+        }
+    }
+}
+
+/**
+Task that waits for a coin or a press of the start button and breaks the executor loop
+*/
+pub async fn wait_for_player_task() {
+    select_biased! {
+        _ = credit::wait_for_coin().fuse() => {},
+        _ = Button::Start.wait_for_press().fuse() => {},
+    }
+
+    // break the executor loop to re-home the gantry
+    wake_task(0xFFFF);
+}
@@ -1,26 +1,120 @@
 //! This file holds the logic for the two UI buttons that the user can interact with
 //! The logic is pretty simple as the UI buttons just advance the state of the system
 //!
-//! The ISR manages whether a button should be pressable or not
+//! Both buttons are input switches on the pin change bank PCINT1. Each button has a task that sends
+//! its events together with its identity, the game state decides which events it accepts.
+//!
+//! A press is classified into an event
+//! short press => released before the long press time, no second press follows
+//! long press => held for the long press time, the event fires while the button is still held
+//! double press => a second press follows within the double press time
+//!
+//! A short press is only known once the double press time has passed without a second press.
+//!
+//! A button found stuck no longer advances the state of the system
 
-use crate::executor::wake_task;
+use crate::channel::Sender;
+use crate::input_switch::InputSwitch;
+use crate::stuck_switch::{self, Switch};
+use crate::timer::with_timeout;
 use crate::{B_END, B_START};
-use avr_device::interrupt;
+
+/// time a button has to be held for a long press
+pub const LONG_PRESS_MS: u16 = 1000;
+
+/// time after the release of a press in which a second press makes a double press
+pub const DOUBLE_PRESS_MS: u16 = 300;
+
+/**
+The two UI buttons
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Start,
+    End,
+}
 
 /**
-Pin Change interrupt triggered if a game button has been pressed
+All events a button can fire
 */
-#[avr_device::interrupt(atmega2560)]
-#[allow(non_snake_case)]
-fn PCINT1() {
-    interrupt::free(|cs| {
-        let end_btn = B_END.borrow(cs).take().unwrap();
-        let start_btn = B_START.borrow(cs).take().unwrap();
-
-        // check if the pin change interrupt was triggered by button press not release
-        if end_btn.is_high() || start_btn.is_high() {
-            // advance the state of the system by breaking the async executor loop
-            wake_task(0xFFFF)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ButtonEvent {
+    ShortPress,
+    LongPress,
+    DoublePress,
+}
+
+/**
+An event together with the button that fired it
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ButtonPress {
+    pub button: Button,
+    pub event: ButtonEvent,
+}
+
+impl Button {
+    /**
+    Gets the input switch of the button
+    */
+    pub fn input(&self) -> InputSwitch {
+        match self {
+            Button::Start => B_START,
+            Button::End => B_END,
+        }
+    }
+
+    /**
+    Waits until the button has been pressed, presses of a stuck button are ignored
+    */
+    pub async fn wait_for_press(&self) {
+        let input = self.input();
+        loop {
+            input.wait_for_press().await;
+            if !stuck_switch::is_ignored(Switch::Button(*self)) {
+                break;
+            }
+            input.wait_for_release().await;
+        }
+    }
+
+    /**
+    Waits for the next event of the button
+
+    Only presses that start while waiting count, a button that is already held has to be released
+    first. This also keeps a long press from firing again while the button is still held.
+    */
+    pub async fn next_event(&self) -> ButtonEvent {
+        let input = self.input();
+        if input.is_pressed() {
+            input.wait_for_release().await;
+        }
+
+        self.wait_for_press().await;
+        if with_timeout(LONG_PRESS_MS, input.wait_for_release())
+            .await
+            .is_none()
+        {
+            return ButtonEvent::LongPress;
+        }
+
+        if with_timeout(DOUBLE_PRESS_MS, self.wait_for_press())
+            .await
+            .is_none()
+        {
+            return ButtonEvent::ShortPress;
         }
-    });
+        input.wait_for_release().await;
+        ButtonEvent::DoublePress
+    }
+}
+
+/**
+Task that sends every event of a button, both buttons can share one channel
+*/
+pub async fn button_task(button: Button, sender: Sender<'_, ButtonPress>) {
+    loop {
+        let event = button.next_event().await;
+        sender.send(ButtonPress { button, event });
+    }
 }
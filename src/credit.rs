@@ -0,0 +1,227 @@
+//! This module holds the coin acceptor and the credits of the claw machine
+//!
+//! The coin acceptor pulls its output low for a short pulse for every coin, the pulses are counted
+//! by the external interrupt INT4. Once enough coins for a play have been inserted they turn into a
+//! credit, every credit pays for a single game. Pulses closer together than the debounce window
+//! are taken as bounces of the same pulse.
+//!
+//! Bonus plays are granted for every n paid plays, in free play every game is free and the credits
+//! stay untouched.
+//!
+//! How to use:
+//! Initialize the coin acceptor during setup, take a credit whenever a game starts
+
+use arduino_hal::hal::port::PE4;
+use arduino_hal::port::mode::{Input, PullUp};
+use arduino_hal::port::Pin;
+use avr_device::atmega2560::EXINT;
+use avr_device::interrupt;
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::executor::{wake_task, ExtWaker};
+use crate::log::debug;
+use crate::settings;
+use crate::stats;
+use crate::timer::PrecisionTicker;
+use crate::Mutex;
+
+/// the bit of INT4 within the EIMSK register
+const INT4_MASK: u8 = 1 << 4;
+
+/// the ISC4 bits within the EICRB register
+const ISC4_MASK: u8 = 0b11;
+
+/// ISC4 bits for an interrupt on the falling edge
+const ISC4_FALLING_EDGE: u8 = 0b10;
+
+/**
+Prices and modes of the machine
+coins per play => coins that turn into a single credit
+bonus every => paid plays after which a bonus play is granted, 0 grants no bonus plays
+free play => games start without credits
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CreditConfig {
+    pub coins_per_play: u8,
+    pub bonus_every: u8,
+    pub free_play: bool,
+}
+
+impl Default for CreditConfig {
+    fn default() -> Self {
+        Self {
+            coins_per_play: 1,
+            bonus_every: 0,
+            free_play: false,
+        }
+    }
+}
+
+/// plays the player has paid for and not played yet
+static CREDITS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// coins that have been inserted but are not enough for a play yet
+static COINS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// paid plays since the last bonus play
+static PAID_PLAYS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// the task waiting for a coin, initialized with an invalid waker ID
+static COIN_TASK: Mutex<Cell<usize>> = Mutex::new(Cell::new(0xFFFF));
+
+/// time of the last accepted pulse in milliseconds
+static LAST_PULSE_MS: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/**
+Configures INT4 to count the pulses of the coin acceptor
+
+The pin only has to be configured as input, the interrupt reads the pulses on its own
+*/
+pub fn init(exint: &EXINT, _coin_pin: Pin<Input<PullUp>, PE4>) {
+    exint
+        .eicrb
+        .modify(|r, w| unsafe { w.bits((r.bits() & !ISC4_MASK) | ISC4_FALLING_EDGE) });
+    // a pending flag from the configuration would count as a coin
+    exint.eifr.write(|w| unsafe { w.bits(INT4_MASK) });
+    exint
+        .eimsk
+        .modify(|r, w| unsafe { w.bits(r.bits() | INT4_MASK) });
+}
+
+/**
+Gets the credits that are left
+*/
+pub fn credits() -> u8 {
+    interrupt::free(|cs| CREDITS.borrow(cs).get())
+}
+
+/**
+Checks whether a game can be started
+*/
+pub fn available() -> bool {
+    settings::current().credit.free_play || credits() > 0
+}
+
+/**
+Takes the credit for a game, in free play no credit is taken
+
+RETURNS: false if no credit is left
+*/
+pub fn take_credit() -> bool {
+    interrupt::free(|cs| {
+        if settings::current().credit.free_play {
+            return true;
+        }
+        let credits = CREDITS.borrow(cs);
+        match credits.get() {
+            0 => false,
+            left => {
+                credits.set(left - 1);
+                true
+            }
+        }
+    })
+}
+
+/**
+Future of wait_for_coin, task is the task registered for the next coin once it has been polled
+*/
+struct CoinWait {
+    task: Option<usize>,
+}
+
+impl Future for CoinWait {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        interrupt::free(|cs| {
+            let coin_task = COIN_TASK.borrow(cs);
+            if self.task.is_some() && coin_task.get() == 0xFFFF {
+                self.task = None;
+                Poll::Ready(())
+            } else {
+                let task = cx.waker().task();
+                coin_task.set(task);
+                self.task = Some(task);
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for CoinWait {
+    fn drop(&mut self) {
+        // a cancelled wait must not leave its task behind, the coin of a later state would wake
+        // whichever task holds the index then
+        if let Some(task) = self.task {
+            interrupt::free(|cs| {
+                let coin_task = COIN_TASK.borrow(cs);
+                if coin_task.get() == task {
+                    coin_task.set(0xFFFF);
+                }
+            })
+        }
+    }
+}
+
+/**
+Waits until the next coin has been inserted
+
+The interrupt clears the waiting task once it has woken it, that tells a coin from other wake ups
+*/
+pub async fn wait_for_coin() {
+    CoinWait { task: None }.await
+}
+
+/**
+Counts a single coin and turns the coins into credits once they pay for a play
+*/
+fn insert_coin(cs: interrupt::CriticalSection) {
+    let config = settings::current().credit;
+    let coins = COINS.borrow(cs);
+    coins.set(coins.get().saturating_add(1));
+    stats::record_coin();
+    if coins.get() < config.coins_per_play {
+        return;
+    }
+    coins.set(0);
+
+    let credits = CREDITS.borrow(cs);
+    credits.set(credits.get().saturating_add(1));
+
+    if config.bonus_every == 0 {
+        return;
+    }
+    let paid_plays = PAID_PLAYS.borrow(cs);
+    paid_plays.set(paid_plays.get() + 1);
+    if paid_plays.get() >= config.bonus_every {
+        paid_plays.set(0);
+        credits.set(credits.get().saturating_add(1));
+    }
+}
+
+/**
+External interrupt triggered by every pulse of the coin acceptor
+*/
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn INT4() {
+    let now_ms = PrecisionTicker::now_ms();
+    interrupt::free(|cs| {
+        let last_pulse_ms = LAST_PULSE_MS.borrow(cs);
+        if now_ms - last_pulse_ms.get() < settings::current().debounce_window_ms as u64 {
+            return;
+        }
+        last_pulse_ms.set(now_ms);
+        insert_coin(cs);
+        debug!("coin, credits: {}", CREDITS.borrow(cs).get());
+
+        let coin_task = COIN_TASK.borrow(cs).replace(0xFFFF);
+        if coin_task != 0xFFFF {
+            wake_task(coin_task)
+        }
+    })
+}
@@ -0,0 +1,11 @@
+//! This module holds the time based debouncing of our switches
+//!
+//! Mechanical contacts bounce for a few milliseconds when they are pressed or released, and every
+//! single bounce triggers a pin change interrupt. A level of a switch only counts once it is still
+//! the same after the debounce window has passed.
+//!
+//! The window is shared by all switches and part of the settings. The limit switches are the
+//! exception, homing stops an axis on the first sampled press instead of stepping on for a window.
+
+/// Debounce window of the default settings
+pub const DEFAULT_WINDOW_MS: u16 = 20;
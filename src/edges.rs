@@ -0,0 +1,79 @@
+//! This module detects the edges of the switches on a pin change bank
+//!
+//! A bank is sampled into a byte, a set bit is a pressed switch. Comparing two samples of the same
+//! bank tells which switches have been pressed and which have been released in between.
+//!
+//! How to use:
+//! Keep the last sample of every bank and detect the edges against every new sample
+
+/**
+The edges of a bank between two samples, a set bit is a switch with that edge
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Edges {
+    pub pressed: u8,
+    pub released: u8,
+}
+
+impl Edges {
+    /// all switches that changed
+    pub fn changed(&self) -> u8 {
+        self.pressed | self.released
+    }
+}
+
+/**
+Detects the edges between the last and the current states of a bank, a set bit is a pressed switch
+*/
+pub fn detect_edges(last: u8, current: u8) -> Edges {
+    let changed = last ^ current;
+    Edges {
+        pressed: changed & current,
+        released: changed & last,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn press() {
+        let edges = detect_edges(0b0000_0000, 0b0000_0100);
+        assert_eq!(edges.pressed, 0b0000_0100);
+        assert_eq!(edges.released, 0);
+        assert_eq!(edges.changed(), 0b0000_0100);
+    }
+
+    #[test]
+    fn release() {
+        let edges = detect_edges(0b1000_0000, 0b0000_0000);
+        assert_eq!(edges.pressed, 0);
+        assert_eq!(edges.released, 0b1000_0000);
+        assert_eq!(edges.changed(), 0b1000_0000);
+    }
+
+    #[test]
+    fn multiple_bits() {
+        // two switches are pressed and one is released while another one is held
+        let edges = detect_edges(0b0001_0001, 0b0000_0111);
+        assert_eq!(edges.pressed, 0b0000_0110);
+        assert_eq!(edges.released, 0b0001_0000);
+        assert_eq!(edges.changed(), 0b0001_0110);
+    }
+
+    #[test]
+    fn no_change() {
+        for state in [0b0000_0000, 0b0101_1010, 0b1111_1111] {
+            let edges = detect_edges(state, state);
+            assert_eq!(
+                edges,
+                Edges {
+                    pressed: 0,
+                    released: 0
+                }
+            );
+            assert_eq!(edges.changed(), 0);
+        }
+    }
+}
@@ -5,6 +5,9 @@ use core::sync::atomic::AtomicU8;
 use core::sync::atomic::Ordering;
 use core::task::{Context, RawWaker, RawWakerVTable, Waker};
 
+/// the most tasks a state can run, every task needs room for its first poll in the task queue
+const MAX_TASKS: usize = 16;
+
 static NUM_TASKS: AtomicU8 = AtomicU8::new(0);
 static TASK_Q: heapless::mpmc::Q16<usize> = heapless::mpmc::Q16::new();
 static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
@@ -61,11 +64,19 @@ this function will run all registered tasks
 once the loop breaks the game advances to the next state
 */
 pub fn run_task(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
+    assert!(tasks.len() <= MAX_TASKS, "Too many tasks: {}", tasks.len());
     NUM_TASKS.store(tasks.len() as u8, Ordering::Relaxed);
+
+    // wakeups left over from the previous state would be replayed into the tasks of this state
+    while TASK_Q.dequeue().is_some() {}
     for task in 0..tasks.len() {
-        TASK_Q.enqueue(task).ok();
+        if TASK_Q.enqueue(task).is_err() {
+            panic!("Task queue full: {}", task);
+        }
     }
 
+    // finished tasks must not be polled again, a stale wakeup would otherwise resume them
+    let mut finished = [false; MAX_TASKS];
     let mut next_state = false;
     while !next_state {
         // while there is a task in queue
@@ -76,10 +87,19 @@ pub fn run_task(tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) {
                 break;
             }
 
+            // skip wakeups of tasks that are finished or belonged to a previous state
+            if task >= tasks.len() || finished[task] {
+                continue;
+            }
+
             // get task from array and make progress at that task
-            let _ = tasks[task]
+            if tasks[task]
                 .as_mut()
-                .poll(&mut Context::from_waker(&get_waker(task)));
+                .poll(&mut Context::from_waker(&get_waker(task)))
+                .is_ready()
+            {
+                finished[task] = true;
+            }
         }
         // else sleep
         sleep();
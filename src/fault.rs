@@ -0,0 +1,89 @@
+//! This module keeps track of faults that happen while the machine is running
+//!
+//! A fault is logged as an error, counted in the statistics and kept as the last fault, so
+//! the machine can recover and the operator can still find out what went wrong.
+
+use avr_device::interrupt;
+use core::cell::Cell;
+
+use crate::log::error;
+use crate::stats;
+use crate::stuck_switch::Switch;
+use crate::Mutex;
+
+/**
+All possible faults
+drop timeout => the claw did not reach the bottom in time
+lift timeout => the claw did not reach the top limit switch in time
+return timeout => the gantry did not reach the prize chute in time
+homing timeout => the gantry did not reach its limit switches in time
+stuck switch => a switch has been active for too long and is ignored for the rest of the game
+invalid settings => the settings in the EEPROM did not load, the defaults are used
+invalid statistics => the statistics in the EEPROM did not load, the counters start at zero
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DropTimeout,
+    LiftTimeout,
+    ReturnTimeout,
+    HomingTimeout,
+    StuckSwitch(Switch),
+    InvalidSettings,
+    InvalidStatistics,
+}
+
+/// number of kinds of faults, a stuck switch counts as one kind for all switches
+pub const KINDS: usize = 7;
+
+/// the names of all kinds of faults, indexed by Fault::index
+pub const KIND_NAMES: [&str; KINDS] = [
+    "drop timeout",
+    "lift timeout",
+    "return timeout",
+    "homing timeout",
+    "stuck switch",
+    "invalid settings",
+    "invalid statistics",
+];
+
+impl Fault {
+    pub fn index(&self) -> usize {
+        match self {
+            Fault::DropTimeout => 0,
+            Fault::LiftTimeout => 1,
+            Fault::ReturnTimeout => 2,
+            Fault::HomingTimeout => 3,
+            Fault::StuckSwitch(_) => 4,
+            Fault::InvalidSettings => 5,
+            Fault::InvalidStatistics => 6,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        KIND_NAMES[self.index()]
+    }
+}
+
+/// The last fault that has been reported
+static LAST_FAULT: Mutex<Cell<Option<Fault>>> = Mutex::new(Cell::new(None));
+
+/**
+Logs a fault as an error and keeps it as the last fault
+*/
+pub fn report(fault: Fault) {
+    interrupt::free(|cs| {
+        LAST_FAULT.borrow(cs).set(Some(fault));
+        stats::record_fault(fault);
+    });
+    match fault {
+        Fault::StuckSwitch(switch) => error!("fault: {} ({})", fault.name(), switch.name()),
+        _ => error!("fault: {}", fault.name()),
+    }
+}
+
+/**
+Gets the last fault that has been reported
+*/
+pub fn last() -> Option<Fault> {
+    interrupt::free(|cs| LAST_FAULT.borrow(cs).get())
+}
@@ -1,41 +1,218 @@
-use crate::channel::Channel;
-use crate::executor;
-use crate::executor::wake_task;
-use crate::joystick::{joystick_switch_task, JoystickDirection};
-use crate::stepper::StepperDirection;
-use arduino_hal::hal::port::{Dynamic, PE3};
-use arduino_hal::port::mode::{Output, PwmOutput};
+//! This module holds the state machine of the claw machine
+//!
+//! Every state runs its tasks on the executor until one of them breaks the executor loop, the
+//! state then reports an event and the transition table decides the next state.
+//!
+//! Entering a state enables exactly the pin change interrupts of the switches the state needs,
+//! leaving a state disables all of them again.
+//!
+//! How to use:
+//! Build the machine during setup, hand it to a new game and run the game
+
+use crate::attract::{gantry_tour_task, idle_timeout_task, led_pattern_task, wait_for_player_task};
+use crate::button::{button_task, Button, ButtonEvent, ButtonPress};
+use crate::channel::{Channel, Receiver};
+use crate::credit;
+use crate::executor::{self, wake_task};
+use crate::grab::{grab_task, reset_task, GrabOutcome};
+use crate::grip::{GripController, PayoutMode};
+use crate::input_switch::InterruptMasks;
+use crate::log::{info, log_task};
+use crate::play_timer::{self, play_timer_task};
+use crate::servo::Servo;
+use crate::settings;
+use crate::shell::{self, service_task, shell_task, ServiceCommand};
+use crate::stats::{self, save_task};
+use crate::stepper::{gantry, Axis, StepperCommand};
+use crate::stuck_switch::stuck_switch_task;
+use crate::timer::{delay_ms, PrecisionTicker};
+use crate::{Mutex, B_END, B_START, P_CHUTE, X_LIMIT, Y_LIMIT, Z_LIMIT};
+use arduino_hal::hal::port::Dynamic;
+use arduino_hal::port::mode::Output;
 use arduino_hal::port::Pin;
-use arduino_hal::simple_pwm::Timer3Pwm;
 use avr_device::atmega2560::EXINT;
+use avr_device::interrupt;
+use core::cell::Cell;
 use core::pin::pin;
 
+pub use claw_machine::state::{GameEvent, GameState};
+
+#[cfg(feature = "analog-joystick")]
+use crate::analog_joystick::analog_joystick_task;
+#[cfg(feature = "analog-joystick")]
+use arduino_hal::hal::port::{PF0, PF1};
+#[cfg(feature = "analog-joystick")]
+use arduino_hal::port::mode::Analog;
+#[cfg(feature = "analog-joystick")]
+use arduino_hal::Adc;
+
+#[cfg(not(feature = "analog-joystick"))]
+use crate::joystick::joystick_task;
+#[cfg(not(feature = "analog-joystick"))]
+use crate::ramp::speed_ramp_task;
+#[cfg(not(feature = "analog-joystick"))]
+use crate::{J_BACKWARD, J_FORWARD, J_LEFT, J_RIGHT};
+
+/// angle of the claw servo when the claw is open
+pub const CLAW_OPEN_ANGLE: u8 = 0;
+
+/// one strong grip every n plays until the operator changes the settings
+pub const PAYOUT_MODE: PayoutMode = PayoutMode::EveryNth(4);
+
+/// the state the game is currently in
+static STATE: Mutex<Cell<GameState>> = Mutex::new(Cell::new(GameState::RESETTING));
+
 /**
-All possible game states
-idle => machine resets and is ready for a new round
-running => one is currently playing the game
-finished => one has finished tha game and machine resets
+Gets the state the game is currently in
 */
+pub fn current_state() -> GameState {
+    interrupt::free(|cs| STATE.borrow(cs).get())
+}
 
-pub enum GameState {
-    IDLE,
-    RUNNING,
-    FINISHED,
+/**
+The parts of a state that depend on the hardware of the machine, the transitions are kept in the
+library
+*/
+trait StateActions {
+    fn interrupt_masks(&self) -> InterruptMasks;
+    fn accepts(&self, press: ButtonPress) -> bool;
+    fn unlocks_service(&self, press: ButtonPress) -> bool;
+    fn pay(&self) -> bool;
+}
+
+impl StateActions for GameState {
+    /**
+    Gets the pin change interrupts the state needs
+    */
+    fn interrupt_masks(&self) -> InterruptMasks {
+        match self {
+            GameState::RESETTING => InterruptMasks::NONE
+                .with(X_LIMIT)
+                .with(Y_LIMIT)
+                .with(Z_LIMIT),
+            // the service menu homes the gantry while idle
+            GameState::IDLE => InterruptMasks::NONE
+                .with(B_START)
+                .with(B_END)
+                .with(X_LIMIT)
+                .with(Y_LIMIT)
+                .with(Z_LIMIT),
+            GameState::ATTRACT => InterruptMasks::NONE.with(B_START),
+            #[cfg(not(feature = "analog-joystick"))]
+            GameState::RUNNING => InterruptMasks::NONE
+                .with(J_RIGHT)
+                .with(J_LEFT)
+                .with(J_FORWARD)
+                .with(J_BACKWARD)
+                .with(B_START)
+                .with(B_END),
+            #[cfg(feature = "analog-joystick")]
+            GameState::RUNNING => InterruptMasks::NONE.with(B_START).with(B_END),
+            // only the limit switches and the prize chute sensor are needed during the grab
+            GameState::FINISHED => InterruptMasks::NONE
+                .with(X_LIMIT)
+                .with(Y_LIMIT)
+                .with(Z_LIMIT)
+                .with(P_CHUTE),
+        }
+    }
+
+    /**
+    Checks whether a button press advances the game in this state
+
+    Only the start button starts a game and only the end button ends it, a long press of the start
+    button unlocks the service commands instead
+    */
+    fn accepts(&self, press: ButtonPress) -> bool {
+        match (self, press.button, press.event) {
+            (GameState::IDLE, Button::Start, ButtonEvent::LongPress) => false,
+            (GameState::IDLE, Button::Start, _) => true,
+            (GameState::RUNNING, Button::End, _) => true,
+            _ => false,
+        }
+    }
+
+    /**
+    Checks whether a button press unlocks the service commands of the shell, an operator holds the
+    start button while the machine is idle
+    */
+    fn unlocks_service(&self, press: ButtonPress) -> bool {
+        *self == GameState::IDLE
+            && press.button == Button::Start
+            && press.event == ButtonEvent::LongPress
+    }
+
+    /**
+    Pays for leaving the state, only starting a game takes a credit
+
+    RETURNS: false if no credit is left to start a game
+    */
+    fn pay(&self) -> bool {
+        match self {
+            GameState::IDLE => credit::take_credit(),
+            _ => true,
+        }
+    }
+}
+
+/**
+Waits until a button press is accepted and paid for by the given state and advances the game, a
+press that unlocks the service commands is handed to the shell
+*/
+pub async fn wait_for_button(mut receiver: Receiver<'_, ButtonPress>, state: GameState) {
+    loop {
+        let press = receiver.receive().await;
+        if state.unlocks_service(press) {
+            shell::unlock();
+        } else if state.accepts(press) && state.pay() {
+            break;
+        }
+    }
+
+    // break the executor loop to advance to the next state
+    wake_task(0xFFFF);
+}
+
+/**
+All the hardware the game drives
+*/
+pub struct Machine {
+    pub x_axis: Axis,
+    pub y_axis: Axis,
+    pub z_axis: Axis,
+    pub claw: Servo,
+    pub start_led: Pin<Output, Dynamic>,
+    pub end_led: Pin<Output, Dynamic>,
+    #[cfg(feature = "analog-joystick")]
+    pub adc: Adc,
+    #[cfg(feature = "analog-joystick")]
+    pub x_stick: Pin<Analog, PF0>,
+    #[cfg(feature = "analog-joystick")]
+    pub y_stick: Pin<Analog, PF1>,
 }
 
 /**
 struct for the game and its logic
 */
-pub(crate) struct Game {
+pub struct Game {
     state: GameState,
     exint: EXINT,
+    machine: Machine,
+    grip: GripController,
 }
 
 impl Game {
-    pub fn new(exint: EXINT) -> Self {
+    pub fn new(exint: EXINT, machine: Machine) -> Self {
+        let settings = settings::current();
         Self {
-            state: GameState::IDLE,
+            state: GameState::RESETTING,
             exint,
+            machine,
+            grip: GripController::new(
+                settings.strong_grip,
+                settings.weak_grip,
+                settings.payout_mode,
+            ),
         }
     }
 
@@ -46,108 +223,336 @@ impl Game {
 
     RETURNS: ! (never returns)
     */
-    pub fn run(
-        &mut self,
-        x_stepper_pulse: Pin<Output, Dynamic>,
-        x_stepper_direction: Pin<Output, Dynamic>,
-        y_stepper_pulse: Pin<Output, Dynamic>,
-        y_stepper_direction: Pin<Output, Dynamic>,
-        y_stepper_pulse_inverted: Pin<Output, Dynamic>,
-        y_stepper_direction_inverted: Pin<Output, Dynamic>,
-        z_stepper_pulse: Pin<Output, Dynamic>,
-        z_stepper_direction: Pin<Output, Dynamic>,
-        mut claw_pwm: Pin<PwmOutput<Timer3Pwm>, PE3>,
-    ) -> ! {
+    pub fn run(&mut self) -> ! {
         loop {
-            match self.state {
-                GameState::IDLE => {
-                    // before idle the games resets its physical state
-                    // this will be run at the start of the program and also after each finished
-                    // game
-
-                    // enable limit switch interrupts
-                    self.exint.pcicr.write(|w| unsafe { w.bits(0b100) });
-                    self.exint.pcmsk2.write(|w| w.bits(0b00000111));
-
-                    let reset_task = pin!(reset_game());
-                    executor::run_task(&mut [reset_task]);
-                    claw_pwm.enable();
-                    claw_pwm.set_duty(255);
-
-                    // enable UI button interrupts and disable limit switch interrupts
-                    self.exint.pcicr.write(|w| unsafe { w.bits(0b010) });
-                    self.exint.pcmsk1.write(|w| w.bits(0b00000010));
-
-                    // executor execute ui buttons task
-                    executor::run_task(&mut []);
-
-                    // once executor loop breaks change game state
-                    self.state = GameState::RUNNING
-                }
-                GameState::RUNNING => {
-                    // enable all interrupts except limit switches
-                    self.exint.pcicr.write(|w| unsafe { w.bits(0b011) });
-                    // Joystick pc interrupt pins
-                    self.exint.pcmsk0.write(|w| w.bits(0b00001111));
-                    // end button interrupt pin
-                    self.exint.pcmsk1.write(|w| w.bits(0b00000100));
-
-                    let x_channel: Channel<StepperDirection> = Channel::new();
-                    let y_channel: Channel<StepperDirection> = Channel::new();
-
-                    // create all joystick tasks
-                    let joystick_right_task = pin!(joystick_switch_task(
-                        JoystickDirection::RIGHT,
-                        x_channel.get_sender()
-                    ));
-                    let joystick_left_task = pin!(joystick_switch_task(
-                        JoystickDirection::LEFT,
-                        x_channel.get_sender()
-                    ));
-                    let joystick_forward_task = pin!(joystick_switch_task(
-                        JoystickDirection::FORWARD,
-                        y_channel.get_sender()
-                    ));
-                    let joystick_backward_task = pin!(joystick_switch_task(
-                        JoystickDirection::BACKWARD,
-                        y_channel.get_sender()
-                    ));
-
-                    /*let x_axis_task = pin!(stepper_task_x(
-                        x_stepper_pulse,
-                        x_stepper_direction,
-                        x_channel.get_receiver()
-                    ));
-                    */
-
-                    executor::run_task(&mut [
-                        joystick_right_task,
-                        joystick_left_task,
-                        joystick_forward_task,
-                        joystick_backward_task,
-                    ]);
-
-                    self.state = GameState::FINISHED
-                }
-                GameState::FINISHED => {
-                    // disable all interrupts
-                    self.exint.pcicr.write(|w| unsafe { w.bits(0b000) });
-                    self.state = GameState::IDLE;
-
-                    // move claw down and close claw
-                    claw_pwm.set_duty(155);
-                }
+            self.enter();
+            let event = match self.state {
+                GameState::RESETTING => self.reset(),
+                GameState::IDLE => self.idle(),
+                GameState::ATTRACT => self.attract(),
+                GameState::RUNNING => self.play(),
+                GameState::FINISHED => self.grab(),
+            };
+            self.exit();
+
+            // an event the table does not know leaves the machine in an unknown state
+            self.state = self.state.next(event).unwrap_or(GameState::RESETTING);
+            info!("state: {}", self.state.name());
+        }
+    }
+
+    /**
+    Entry action of every state, publishes the state and enables the interrupts of the state
+    */
+    fn enter(&self) {
+        interrupt::free(|cs| STATE.borrow(cs).set(self.state));
+        self.state.interrupt_masks().apply(&self.exint);
+    }
+
+    /**
+    Exit action of every state, disables all pin change interrupts
+    */
+    fn exit(&self) {
+        InterruptMasks::NONE.apply(&self.exint);
+    }
+
+    /**
+    Homes all axes, this runs at the start of the program and after each finished game
+
+    Changed ranges of the axes and a changed calibration of the claw apply from here on
+    */
+    fn reset(&mut self) -> GameEvent {
+        let machine = &mut self.machine;
+        let settings = settings::current();
+        machine.x_axis.set_max_steps(settings.stepper.max_x_steps);
+        machine.y_axis.set_max_steps(settings.stepper.max_y_steps);
+        machine.z_axis.set_max_steps(settings.stepper.max_z_steps);
+        machine.claw.set_calibration(settings.servo);
+
+        let reset_task = pin!(reset_task(
+            &mut machine.x_axis,
+            &mut machine.y_axis,
+            &mut machine.z_axis,
+            &mut machine.claw
+        ));
+        let shell_task = pin!(shell_task(None));
+        let log_task = pin!(log_task());
+        executor::run_task(&mut [reset_task, shell_task, log_task]);
+
+        GameEvent::Homed
+    }
+
+    /**
+    Waits for the player to press the start button, a game only starts with a credit or in free play
+
+    Nobody playing for the idle period starts the attract mode
+    */
+    fn idle(&mut self) -> GameEvent {
+        let machine = &mut self.machine;
+
+        let mut timed_out = false;
+        // the blink task borrows the LED and the idle timeout task the flag, so they have to be
+        // dropped before switching the LED off
+        {
+            let button_channel: Channel<ButtonPress> = Channel::new();
+            let start_task = pin!(button_task(Button::Start, button_channel.get_sender()));
+            let end_task = pin!(button_task(Button::End, button_channel.get_sender()));
+            let wait_for_start_task = pin!(wait_for_button(
+                button_channel.get_receiver(),
+                GameState::IDLE
+            ));
+            let blink_led_task = pin!(blink_led(&mut machine.start_led));
+            let idle_timeout_task = pin!(idle_timeout_task(&mut timed_out));
+            let save_task = pin!(save_task());
+
+            // the service menu moves the machine only while idle
+            let service_channel: Channel<ServiceCommand> = Channel::new();
+            let service_task = pin!(service_task(
+                service_channel.get_receiver(),
+                &mut machine.x_axis,
+                &mut machine.y_axis,
+                &mut machine.z_axis,
+                &mut machine.claw
+            ));
+            let shell_task = pin!(shell_task(Some(service_channel.get_sender())));
+            let log_task = pin!(log_task());
+            executor::run_task(&mut [
+                start_task,
+                end_task,
+                wait_for_start_task,
+                blink_led_task,
+                idle_timeout_task,
+                save_task,
+                service_task,
+                shell_task,
+                log_task,
+            ]);
+        }
+
+        machine.start_led.set_low();
+        if timed_out {
+            GameEvent::IdleTimeout
+        } else {
+            GameEvent::StartPressed
+        }
+    }
+
+    /**
+    Runs the light patterns and the gantry tours until a coin is inserted or the start button is
+    pressed
+    */
+    fn attract(&mut self) -> GameEvent {
+        let machine = &mut self.machine;
+        // the pattern task borrows the LEDs, so it has to be dropped before switching them off
+        {
+            let led_pattern_task = pin!(led_pattern_task(
+                &mut machine.start_led,
+                &mut machine.end_led
+            ));
+            let gantry_tour_task = pin!(gantry_tour_task(&mut machine.x_axis, &mut machine.y_axis));
+            let wait_for_player_task = pin!(wait_for_player_task());
+            let save_task = pin!(save_task());
+            let shell_task = pin!(shell_task(None));
+            let log_task = pin!(log_task());
+            executor::run_task(&mut [
+                led_pattern_task,
+                gantry_tour_task,
+                wait_for_player_task,
+                save_task,
+                shell_task,
+                log_task,
+            ]);
+        }
+
+        machine.start_led.set_low();
+        machine.end_led.set_low();
+        GameEvent::PlayerArrived
+    }
+
+    /**
+    Lets the player move the gantry until the end button is pressed or the time is over
+    */
+    fn play(&mut self) -> GameEvent {
+        let machine = &mut self.machine;
+        #[cfg(not(feature = "analog-joystick"))]
+        let ramp = settings::current().ramp;
+        let started_ms = PrecisionTicker::now_ms();
+
+        // the play timer task borrows the end LED, so the tasks have to be dropped before the LED is
+        // switched off
+        {
+            // channels for both x-and y-axis
+            let x_channel: Channel<StepperCommand> = Channel::new();
+            let y_channel: Channel<StepperCommand> = Channel::new();
+
+            let x_gantry_task = pin!(gantry(x_channel.get_receiver(), &mut machine.x_axis));
+            let y_gantry_task = pin!(gantry(y_channel.get_receiver(), &mut machine.y_axis));
+
+            // one task reads the whole joystick and drives both axes, the digital joystick does so
+            // through a speed ramp per axis
+            #[cfg(not(feature = "analog-joystick"))]
+            let x_joystick_channel: Channel<StepperCommand> = Channel::new();
+            #[cfg(not(feature = "analog-joystick"))]
+            let y_joystick_channel: Channel<StepperCommand> = Channel::new();
+            #[cfg(not(feature = "analog-joystick"))]
+            let joystick_task = pin!(joystick_task(
+                x_joystick_channel.get_sender(),
+                y_joystick_channel.get_sender()
+            ));
+            #[cfg(not(feature = "analog-joystick"))]
+            let x_ramp_task = pin!(speed_ramp_task(
+                x_joystick_channel.get_receiver(),
+                x_channel.get_sender(),
+                ramp
+            ));
+            #[cfg(not(feature = "analog-joystick"))]
+            let y_ramp_task = pin!(speed_ramp_task(
+                y_joystick_channel.get_receiver(),
+                y_channel.get_sender(),
+                ramp
+            ));
+            #[cfg(feature = "analog-joystick")]
+            let joystick_task = pin!(analog_joystick_task(
+                &mut machine.adc,
+                &machine.x_stick,
+                &machine.y_stick,
+                settings::current().analog,
+                x_channel.get_sender(),
+                y_channel.get_sender()
+            ));
+
+            // flags switches that are active for too long
+            let stuck_switch_task = pin!(stuck_switch_task());
+
+            // ends the game once the time is over and shows the remaining time on the end button LED
+            let play_timer_task = pin!(play_timer_task(&mut machine.end_led));
+
+            // answers the operator, the machine is busy
+            let shell_task = pin!(shell_task(None));
+            let log_task = pin!(log_task());
+
+            // ends the game once the player presses the end button
+            let button_channel: Channel<ButtonPress> = Channel::new();
+            let start_task = pin!(button_task(Button::Start, button_channel.get_sender()));
+            let end_task = pin!(button_task(Button::End, button_channel.get_sender()));
+            let wait_for_end_task = pin!(wait_for_button(
+                button_channel.get_receiver(),
+                GameState::RUNNING
+            ));
+
+            #[cfg(not(feature = "analog-joystick"))]
+            executor::run_task(&mut [
+                joystick_task,
+                x_ramp_task,
+                y_ramp_task,
+                x_gantry_task,
+                y_gantry_task,
+                stuck_switch_task,
+                start_task,
+                end_task,
+                wait_for_end_task,
+                play_timer_task,
+                shell_task,
+                log_task,
+            ]);
+            #[cfg(feature = "analog-joystick")]
+            executor::run_task(&mut [
+                joystick_task,
+                x_gantry_task,
+                y_gantry_task,
+                stuck_switch_task,
+                start_task,
+                end_task,
+                wait_for_end_task,
+                play_timer_task,
+                shell_task,
+                log_task,
+            ]);
+        }
+
+        play_timer::stop();
+        machine.end_led.set_low();
+        stats::record_play(PrecisionTicker::now_ms() - started_ms);
+        if play_timer::expired() {
+            GameEvent::TimeUp
+        } else {
+            GameEvent::EndPressed
+        }
+    }
+
+    /**
+    Drops, grabs and returns with the grip decided for this game
+    */
+    fn grab(&mut self) -> GameEvent {
+        let machine = &mut self.machine;
+        let settings = settings::current();
+        self.grip.configure(
+            settings.strong_grip,
+            settings.weak_grip,
+            settings.payout_mode,
+        );
+
+        let mut outcome = GrabOutcome::Lost;
+        // the grab task borrows the outcome, so it has to be dropped before reading it
+        {
+            let grab_task = pin!(grab_task(
+                &mut machine.x_axis,
+                &mut machine.y_axis,
+                &mut machine.z_axis,
+                &mut machine.claw,
+                self.grip.decide(),
+                &settings.grab,
+                &mut outcome
+            ));
+            let shell_task = pin!(shell_task(None));
+            let log_task = pin!(log_task());
+            executor::run_task(&mut [grab_task, shell_task, log_task]);
+        }
+
+        self.grip.record_outcome(outcome);
+        info!(
+            "game {}",
+            if outcome == GrabOutcome::Won {
+                "won"
+            } else {
+                "lost"
             }
+        );
+        if outcome == GrabOutcome::Won {
+            stats::record_win();
+            let win_effect_task = pin!(win_effect(&mut machine.start_led, &mut machine.end_led));
+            executor::run_task(&mut [win_effect_task]);
         }
+
+        GameEvent::Grabbed
     }
 }
 
-async fn reset_game() {
-    // rollback z motor till limit switch
-    // rollback x motor till limit switch
-    // rollback y motor till limit switch
-    // release claw
+/**
+Blinks the start button LED while the machine waits for a player, the LED stays off while no game
+can be started
+*/
+async fn blink_led(led: &mut Pin<Output, Dynamic>) {
+    loop {
+        if credit::available() {
+            led.toggle();
+        } else {
+            led.set_low();
+        }
+        delay_ms(500).await;
+    }
+}
+
+/**
+Blinks both UI button LEDs after a prize has been won
+*/
+async fn win_effect(start_led: &mut Pin<Output, Dynamic>, end_led: &mut Pin<Output, Dynamic>) {
+    for _ in 0..6 {
+        start_led.toggle();
+        end_led.toggle();
+        delay_ms(250).await;
+    }
 
-    // break the executor loop to advance to idle state
+    // break the executor loop to advance to the next state
     wake_task(0xFFFF);
 }
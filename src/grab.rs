@@ -0,0 +1,234 @@
+//! This module holds the grab sequence that runs once a game has finished
+//!
+//! The sequence follows the states of our first sketch
+//!
+//! dropping: lower the claw to the bottom, pause and close the claw
+//! lifting: raise the claw until the z limit switch is pressed
+//! returning: move the gantry to the prize chute, open the claw and home all axes
+//!
+//! While the claw opens above the chute the prize chute sensor is watched, a prize passing the
+//! sensor within the timeout means the game has been won.
+//!
+//! Every movement is raced against a timeout, so a blocked axis or a broken limit switch results in
+//! a fault instead of a machine that hangs forever.
+
+use core::future::join;
+
+use crate::executor::wake_task;
+use crate::fault::{report, Fault};
+use crate::game::CLAW_OPEN_ANGLE;
+use crate::grip::GripProfile;
+use crate::prize_chute;
+use crate::servo::{Easing, Servo};
+use crate::stepper::{Axis, MAX_Z_STEPS};
+use crate::timer::{delay_ms, with_timeout};
+use crate::{X_LIMIT, Y_LIMIT, Z_LIMIT};
+
+/// position of the prize chute on the x-axis
+const CHUTE_X: i32 = 0;
+
+/// position of the prize chute on the y-axis
+const CHUTE_Y: i32 = 0;
+
+/// half of the period of one step while homing, slow to not crash into the limit switches
+const HOMING_HALF_PERIOD_US: u16 = 1500;
+
+const RETURN_TIMEOUT_MS: u16 = 8000;
+const HOMING_TIMEOUT_MS: u16 = 15000;
+
+/**
+Parameters of the grab sequence
+
+Operators can tune the sequence to get variants like a claw that re-squeezes at the top or holds
+for a while above the chute before it opens
+*/
+#[derive(Clone, Copy)]
+pub struct GrabConfig {
+    /// steps the pulley lowers the claw, clamped to the range of the z-axis
+    pub drop_depth: i32,
+    /// half of the period of one step of the pulley while dropping
+    pub drop_half_period_us: u16,
+    /// half of the period of one step of the pulley while lifting
+    pub lift_half_period_us: u16,
+    /// half of the period of one step while returning to the prize chute
+    pub return_half_period_us: u16,
+    /// pause at the bottom before the claw closes
+    pub close_delay_ms: u16,
+    /// duration of closing and opening the claw
+    pub claw_move_ms: u16,
+    /// hold at the bottom after the claw has closed before lifting
+    pub bottom_hold_ms: u16,
+    /// how often the claw re-squeezes once it has been lifted
+    pub regrip_count: u8,
+    /// degrees the claw opens before each re-squeeze
+    pub regrip_release_deg: u8,
+    /// hold above the prize chute before the claw opens
+    pub chute_hold_ms: u16,
+    /// time a prize has to reach the chute sensor after the claw started opening
+    pub prize_timeout_ms: u16,
+}
+
+impl Default for GrabConfig {
+    fn default() -> Self {
+        Self {
+            drop_depth: MAX_Z_STEPS,
+            drop_half_period_us: 800,
+            lift_half_period_us: 800,
+            return_half_period_us: 1000,
+            close_delay_ms: 500,
+            claw_move_ms: 600,
+            bottom_hold_ms: 500,
+            regrip_count: 0,
+            regrip_release_deg: 15,
+            chute_hold_ms: 0,
+            prize_timeout_ms: 3000,
+        }
+    }
+}
+
+/**
+Timeout for moving the pulley n steps, twice the expected duration plus one second
+*/
+fn z_timeout_ms(steps: i32, half_period_us: u16) -> u16 {
+    let expected_ms = steps as u32 * 2 * half_period_us as u32 / 1000;
+    (2 * expected_ms + 1000).min(u16::MAX as u32) as u16
+}
+
+/**
+All possible outcomes of a grab
+won => the prize chute sensor detected a prize
+lost => no prize reached the chute in time
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GrabOutcome {
+    Won,
+    Lost,
+}
+
+/**
+Runs the whole grab sequence with the grip decided for this game
+*/
+pub async fn grab_sequence(
+    x_axis: &mut Axis,
+    y_axis: &mut Axis,
+    z_axis: &mut Axis,
+    claw: &mut Servo,
+    grip: GripProfile,
+    config: &GrabConfig,
+) -> Result<GrabOutcome, Fault> {
+    // dropping
+    let depth = config.drop_depth.clamp(0, z_axis.max_steps());
+    with_timeout(
+        z_timeout_ms(depth, config.drop_half_period_us),
+        z_axis.move_to(depth, config.drop_half_period_us),
+    )
+    .await
+    .ok_or(Fault::DropTimeout)?;
+    delay_ms(config.close_delay_ms).await;
+    claw.move_to_angle(grip.closed_angle, config.claw_move_ms, Easing::EaseInOut)
+        .await;
+    delay_ms(config.bottom_hold_ms).await;
+
+    // lifting
+    with_timeout(
+        z_timeout_ms(z_axis.position(), config.lift_half_period_us),
+        z_axis.home(&Z_LIMIT, config.lift_half_period_us),
+    )
+    .await
+    .ok_or(Fault::LiftTimeout)?;
+    for _ in 0..config.regrip_count {
+        let release_angle = grip.closed_angle.saturating_sub(config.regrip_release_deg);
+        claw.move_to_angle(release_angle, config.claw_move_ms / 2, Easing::Linear)
+            .await;
+        claw.move_to_angle(
+            grip.closed_angle,
+            config.claw_move_ms / 2,
+            Easing::EaseInOut,
+        )
+        .await;
+    }
+    claw.move_to_angle(grip.hold_angle, config.claw_move_ms, Easing::Linear)
+        .await;
+
+    // returning
+    with_timeout(
+        RETURN_TIMEOUT_MS,
+        join!(
+            x_axis.move_to(CHUTE_X, config.return_half_period_us),
+            y_axis.move_to(CHUTE_Y, config.return_half_period_us)
+        ),
+    )
+    .await
+    .ok_or(Fault::ReturnTimeout)?;
+    delay_ms(config.chute_hold_ms).await;
+    prize_chute::arm();
+    let (_, prize) = join!(
+        claw.move_to_angle(CLAW_OPEN_ANGLE, config.claw_move_ms, Easing::EaseInOut),
+        with_timeout(config.prize_timeout_ms, prize_chute::wait_for_prize())
+    )
+    .await;
+    let outcome = match prize {
+        Some(()) => GrabOutcome::Won,
+        None => GrabOutcome::Lost,
+    };
+
+    home(x_axis, y_axis, z_axis).await?;
+    Ok(outcome)
+}
+
+/**
+Moves all axes to their limit switches, the pulley first so the claw can not hit anything
+*/
+pub async fn home(x_axis: &mut Axis, y_axis: &mut Axis, z_axis: &mut Axis) -> Result<(), Fault> {
+    with_timeout(HOMING_TIMEOUT_MS, async {
+        z_axis.home(&Z_LIMIT, HOMING_HALF_PERIOD_US).await;
+        join!(
+            x_axis.home(&X_LIMIT, HOMING_HALF_PERIOD_US),
+            y_axis.home(&Y_LIMIT, HOMING_HALF_PERIOD_US)
+        )
+        .await;
+    })
+    .await
+    .ok_or(Fault::HomingTimeout)
+}
+
+/**
+Task that runs the grab sequence, stores its outcome and advances the game once it is done
+
+On a fault the claw releases whatever it holds, homing before the next game recovers the gantry
+and the game counts as lost
+*/
+pub async fn grab_task(
+    x_axis: &mut Axis,
+    y_axis: &mut Axis,
+    z_axis: &mut Axis,
+    claw: &mut Servo,
+    grip: GripProfile,
+    config: &GrabConfig,
+    outcome: &mut GrabOutcome,
+) {
+    *outcome = match grab_sequence(x_axis, y_axis, z_axis, claw, grip, config).await {
+        Ok(outcome) => outcome,
+        Err(fault) => {
+            report(fault);
+            claw.set_angle(CLAW_OPEN_ANGLE);
+            GrabOutcome::Lost
+        }
+    };
+
+    // break the executor loop to advance to idle state
+    wake_task(0xFFFF);
+}
+
+/**
+Task that homes all axes before a new game and advances the game once it is done
+*/
+pub async fn reset_task(x_axis: &mut Axis, y_axis: &mut Axis, z_axis: &mut Axis, claw: &mut Servo) {
+    claw.set_angle(CLAW_OPEN_ANGLE);
+    if let Err(fault) = home(x_axis, y_axis, z_axis).await {
+        report(fault);
+    }
+
+    // break the executor loop to advance to the next state
+    wake_task(0xFFFF);
+}
@@ -0,0 +1,145 @@
+//! This module decides how firmly the claw grips
+//!
+//! Like every real claw machine we do not grip firmly in every game. A strong grip closes the claw
+//! fully and keeps it closed, a weak grip closes the claw and relaxes it once the claw has been
+//! lifted, so the prize will most likely slip.
+//!
+//! The payout mode decides per game which grip is used, either every n-th play or with a
+//! probability. Every strong grip is counted in the statistics next to the plays and the prizes
+//! actually won, so operators can audit the actual payout.
+//!
+//! How to use:
+//! Create a GripController and call decide at the start of each grab
+
+use crate::grab::GrabOutcome;
+use crate::stats;
+use crate::timer::PrecisionTicker;
+
+/**
+Servo angles of the claw for one grip
+closed angle => the angle the claw closes to at the bottom
+hold angle => the angle the claw relaxes to once it has been lifted
+*/
+#[derive(Clone, Copy)]
+pub struct GripProfile {
+    pub closed_angle: u8,
+    pub hold_angle: u8,
+}
+
+/// grip that closes the claw fully and keeps it closed
+pub const STRONG_GRIP: GripProfile = GripProfile {
+    closed_angle: 60,
+    hold_angle: 60,
+};
+
+/// grip that closes the claw fully and relaxes once lifted
+pub const WEAK_GRIP: GripProfile = GripProfile {
+    closed_angle: 60,
+    hold_angle: 30,
+};
+
+/**
+All possible payout modes
+always => every game gets a strong grip
+every nth => one strong grip every n plays
+probability => a strong grip with the given probability in percent
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PayoutMode {
+    Always,
+    EveryNth(u16),
+    Probability(u8),
+}
+
+/**
+Controller that decides the grip of each game
+*/
+pub struct GripController {
+    strong: GripProfile,
+    weak: GripProfile,
+    mode: PayoutMode,
+    /// state of the xorshift random number generator
+    random: u16,
+    /// plays since the last strong grip
+    since_strong: u16,
+}
+
+impl GripController {
+    pub fn new(strong: GripProfile, weak: GripProfile, mode: PayoutMode) -> Self {
+        Self {
+            strong,
+            weak,
+            mode,
+            random: 0xACE1,
+            since_strong: 0,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: PayoutMode) {
+        self.mode = mode;
+        self.since_strong = 0;
+    }
+
+    /**
+    Takes over changed settings, the plays since the last strong grip only restart with a new mode
+    */
+    pub fn configure(&mut self, strong: GripProfile, weak: GripProfile, mode: PayoutMode) {
+        self.strong = strong;
+        self.weak = weak;
+        if mode != self.mode {
+            self.set_mode(mode);
+        }
+    }
+
+    /**
+    Decides the grip of the current game, a strong grip is counted in the statistics
+    */
+    pub fn decide(&mut self) -> GripProfile {
+        let strong = match self.mode {
+            PayoutMode::Always => true,
+            PayoutMode::EveryNth(n) => self.since_strong.saturating_add(1) >= n,
+            PayoutMode::Probability(percent) => self.next_random() % 100 < percent as u16,
+        };
+
+        if strong {
+            stats::record_strong_grip();
+            self.since_strong = 0;
+            self.strong
+        } else {
+            // without strong grips, e.g. at 0 percent, the count would overflow
+            self.since_strong = self.since_strong.saturating_add(1);
+            self.weak
+        }
+    }
+
+    /**
+    Records whether the prize chute detected a prize in the current game
+
+    A prize won with a weak grip has been paid out as well, so the next strong grip of the every
+    n-th mode is n plays away again
+    */
+    pub fn record_outcome(&mut self, outcome: GrabOutcome) {
+        if outcome == GrabOutcome::Won {
+            self.since_strong = 0;
+        }
+    }
+
+    /**
+    Gets the next pseudo random number
+
+    The player decides when the claw drops, so mixing in the current tick count makes the sequence
+    unpredictable
+    */
+    fn next_random(&mut self) -> u16 {
+        let mut x = self.random ^ PrecisionTicker::now() as u16;
+        if x == 0 {
+            x = 0xACE1;
+        }
+        // xorshift16
+        x ^= x << 7;
+        x ^= x >> 9;
+        x ^= x << 8;
+        self.random = x;
+        x
+    }
+}
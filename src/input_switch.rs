@@ -0,0 +1,314 @@
+//! This module holds the input layer shared by all switches of the machine
+//!
+//! Every switch sits on one of the three pin change banks of the ATmega2560 and is identified by
+//! its bank and its bit within the bank, which is the bit of its PCMSK register. All our switches
+//! pull their pin low while they are pressed.
+//!
+//! The pin change interrupts can only tell that some pin of a bank changed. Every interrupt samples
+//! the whole bank, compares it with the states seen by the previous interrupt and only wakes the
+//! tasks waiting on a switch that actually changed. The pins stay in the input layer, they are only
+//! ever borrowed for sampling.
+//!
+//! A level only counts once it is still the same after the debounce window has passed.
+//!
+//! How to use:
+//! Create a constant InputSwitch for every switch, attach its pin during setup and wait for the
+//! switch within a task
+
+use arduino_hal::hal::port::Dynamic;
+use arduino_hal::port::mode::{Input, PullUp};
+use arduino_hal::port::Pin;
+use avr_device::atmega2560::EXINT;
+use avr_device::interrupt;
+use claw_machine::edges::{detect_edges, Edges};
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::task::Poll;
+
+use crate::executor::{wake_task, ExtWaker};
+use crate::prize_chute;
+use crate::settings;
+use crate::timer::delay_ms;
+use crate::{Mutex, P_CHUTE};
+
+/// number of switches a single bank can hold
+const BANK_SIZE: usize = 8;
+
+/// number of switches of all three banks
+const SWITCH_COUNT: usize = 3 * BANK_SIZE;
+
+/// the pins of all attached switches, indexed by InputSwitch::index
+static PINS: [Mutex<RefCell<Option<Pin<Input<PullUp>, Dynamic>>>>; SWITCH_COUNT] =
+    [const { Mutex::new(RefCell::new(None)) }; SWITCH_COUNT];
+
+/// the states of each bank seen by the last interrupt, a set bit is a pressed switch
+static LAST_STATES: [Mutex<Cell<u8>>; 3] = [const { Mutex::new(Cell::new(0)) }; 3];
+
+/// the task waiting on each switch, indexed by InputSwitch::index
+/// Gets initialized with an invalid waker ID
+static SWITCH_TASKS: [Mutex<Cell<usize>>; SWITCH_COUNT] =
+    [const { Mutex::new(Cell::new(0xFFFF)) }; SWITCH_COUNT];
+
+/**
+The three pin change banks
+pcint0 => PCINT0..7, used by the joystick
+pcint1 => PCINT8..15, used by the UI buttons
+pcint2 => PCINT16..23, used by the limit switches and the prize chute sensor
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PcintBank {
+    Pcint0,
+    Pcint1,
+    Pcint2,
+}
+
+/**
+A switch on a pin change bank
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct InputSwitch {
+    bank: PcintBank,
+    bit: u8,
+}
+
+impl InputSwitch {
+    pub const fn new(bank: PcintBank, bit: u8) -> Self {
+        Self { bank, bit }
+    }
+
+    fn index(&self) -> usize {
+        self.bank as usize * BANK_SIZE + self.bit as usize
+    }
+
+    /**
+    Gets the bit of the switch within the PCMSK register of its bank
+    */
+    pub const fn mask(&self) -> u8 {
+        1 << self.bit
+    }
+
+    /**
+    Hands the pin of the switch over to the input layer
+    */
+    pub fn attach(&self, pin: Pin<Input<PullUp>, Dynamic>) {
+        interrupt::free(|cs| {
+            let last_state = LAST_STATES[self.bank as usize].borrow(cs);
+            if pin.is_low() {
+                last_state.set(last_state.get() | self.mask());
+            } else {
+                last_state.set(last_state.get() & !self.mask());
+            }
+            *PINS[self.index()].borrow(cs).borrow_mut() = Some(pin);
+        })
+    }
+
+    /**
+    Samples whether the switch is pressed, a switch without a pin is never pressed
+    */
+    pub fn is_pressed(&self) -> bool {
+        interrupt::free(|cs| {
+            PINS[self.index()]
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .map_or(false, |pin| pin.is_low())
+        })
+    }
+
+    /**
+    Wakes the task waiting on this switch, so it samples the switch again
+    */
+    pub fn notify(&self) {
+        interrupt::free(|cs| {
+            let task = SWITCH_TASKS[self.index()].borrow(cs).replace(0xFFFF);
+            if task != 0xFFFF {
+                wake_task(task)
+            }
+        })
+    }
+
+    /**
+    Waits until the switch has been pressed for at least the debounce window
+    */
+    pub async fn wait_for_press(&self) {
+        self.wait_for_stable(true).await
+    }
+
+    /**
+    Waits until the switch has been released for at least the debounce window
+    */
+    pub async fn wait_for_release(&self) {
+        self.wait_for_stable(false).await
+    }
+
+    /**
+    Waits until the switch changes its state for at least the debounce window
+
+    RETURNS: true if the switch has been pressed, false if it has been released
+    */
+    pub async fn wait_for_change(&self) -> bool {
+        let pressed = !self.is_pressed();
+        self.wait_for_stable(pressed).await;
+        pressed
+    }
+
+    async fn wait_for_stable(&self, pressed: bool) {
+        loop {
+            wait_until(&[*self], || self.is_pressed() == pressed).await;
+            delay_ms(settings::current().debounce_window_ms).await;
+            if self.is_pressed() == pressed {
+                break;
+            }
+        }
+    }
+}
+
+/**
+Waits until the condition holds, the condition is checked again on every change of the switches
+
+The task is registered before the condition is checked, so a change in between is not lost
+*/
+pub async fn wait_until(switches: &[InputSwitch], mut condition: impl FnMut() -> bool) {
+    poll_fn(|cx| {
+        let task = cx.waker().task();
+        interrupt::free(|cs| {
+            for switch in switches {
+                SWITCH_TASKS[switch.index()].borrow(cs).set(task);
+            }
+        });
+        if condition() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/**
+The pin change interrupts that are enabled
+pcicr => a bit for every enabled bank
+pcmsk => the enabled switches of each bank
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct InterruptMasks {
+    pub pcicr: u8,
+    pub pcmsk: [u8; 3],
+}
+
+impl InterruptMasks {
+    pub const NONE: InterruptMasks = InterruptMasks {
+        pcicr: 0,
+        pcmsk: [0; 3],
+    };
+
+    /**
+    Enables the interrupt of a switch and of its bank
+    */
+    pub const fn with(mut self, switch: InputSwitch) -> Self {
+        self.pcicr |= 1 << switch.bank as u8;
+        self.pcmsk[switch.bank as usize] |= switch.mask();
+        self
+    }
+
+    /**
+    Writes the masks to the interrupt registers, the banks are disabled while their masks change
+
+    The last states of every enabled bank are sampled again, switches may have changed while their
+    bank was disabled and the interrupts would miss their next edge otherwise
+    */
+    pub fn apply(&self, exint: &EXINT) {
+        interrupt::free(|cs| {
+            exint.pcicr.write(|w| unsafe { w.bits(0) });
+            exint.pcmsk0.write(|w| w.bits(self.pcmsk[0]));
+            exint.pcmsk1.write(|w| w.bits(self.pcmsk[1]));
+            exint.pcmsk2.write(|w| w.bits(self.pcmsk[2]));
+            for bank in [PcintBank::Pcint0, PcintBank::Pcint1, PcintBank::Pcint2] {
+                if self.pcicr & (1 << bank as u8) != 0 {
+                    let last_state = LAST_STATES[bank as usize].borrow(cs);
+                    last_state.set(sample_bank(cs, bank));
+                }
+            }
+            exint.pcicr.write(|w| unsafe { w.bits(self.pcicr) });
+        })
+    }
+}
+
+/**
+Samples all attached switches of a bank, a set bit is a pressed switch
+*/
+fn sample_bank(cs: interrupt::CriticalSection, bank: PcintBank) -> u8 {
+    let mut pressed = 0;
+    for bit in 0..BANK_SIZE {
+        let is_low = PINS[bank as usize * BANK_SIZE + bit]
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .map_or(false, |pin| pin.is_low());
+        if is_low {
+            pressed |= 1 << bit;
+        }
+    }
+    pressed
+}
+
+/**
+Updates the last states of the bank and wakes every task waiting on a switch that changed, a task
+waiting on several switches only once
+
+RETURNS: the edges since the previous interrupt of the bank
+*/
+fn on_pin_change(bank: PcintBank) -> Edges {
+    interrupt::free(|cs| {
+        let current = sample_bank(cs, bank);
+        let last = LAST_STATES[bank as usize].borrow(cs).replace(current);
+        let edges = detect_edges(last, current);
+
+        // a bit for every task that has been woken, the executor runs at most 16 tasks
+        let mut woken: u16 = 0;
+        for bit in 0..BANK_SIZE {
+            if edges.changed() & (1 << bit) == 0 {
+                continue;
+            }
+            let index = bank as usize * BANK_SIZE + bit;
+            let task = SWITCH_TASKS[index].borrow(cs).replace(0xFFFF);
+            if task < 16 && woken & (1 << task) == 0 {
+                wake_task(task);
+                woken |= 1 << task;
+            }
+        }
+        edges
+    })
+}
+
+/**
+Pin Change interrupt triggered if a joystick switch has changed
+*/
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn PCINT0() {
+    on_pin_change(PcintBank::Pcint0);
+}
+
+/**
+Pin Change interrupt triggered if a UI button has changed
+*/
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn PCINT1() {
+    on_pin_change(PcintBank::Pcint1);
+}
+
+/**
+Pin Change interrupt triggered if a limit switch or the prize chute sensor has changed
+*/
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn PCINT2() {
+    let edges = on_pin_change(PcintBank::Pcint2);
+
+    // the prize chute sensor latches its short pulses within the interrupt
+    if edges.pressed & P_CHUTE.mask() != 0 {
+        prize_chute::on_blocked();
+    }
+}
@@ -1,29 +1,40 @@
-use avr_device::interrupt;
-use core::cell::RefCell;
-use core::future::poll_fn;
-use core::task::Poll;
+//! This module reads the arcade joystick
+//!
+//! The joystick consists of four switches, one for each direction, on the pin change bank PCINT0.
+//! A single task reads all four switches into one 8-way direction, so pressing two switches of
+//! different axes moves the claw diagonally with both axes driven at the same time.
+//!
+//! Two opposing switches can only be pressed at once with a broken or badly mounted stick. Instead
+//! of letting the last switch win, opposing switches cancel each other out and the axis stays idle.
+//!
+//! Cabinets are wired and mounted differently, so a mapping table translates in two stages:
+//! every physical switch is assigned a logical direction, and every logical direction is assigned
+//! an axis and a motor direction. The mapping is part of the settings, operators change it with the
+//! map command of the shell.
 
-use crate::channel::Sender;
-use crate::executor::{wake_task, ExtWaker};
+use crate::input_switch::InputSwitch;
 use crate::stepper::StepperDirection;
 use crate::stepper::StepperDirection::{ClockWise, CounterClockWise, Idle};
-use crate::{Mutex, J_BACKWARD, J_FORWARD, J_LEFT, J_RIGHT};
+use crate::{J_BACKWARD, J_FORWARD, J_LEFT, J_RIGHT};
 
-static JOYSTICK_SWITCH_TASKS: [Mutex<RefCell<usize>>; 4] = [
-    Mutex::new(RefCell::new(0xFFFF)),
-    Mutex::new(RefCell::new(0xFFFF)),
-    Mutex::new(RefCell::new(0xFFFF)),
-    Mutex::new(RefCell::new(0xFFFF)),
-];
-
-static JOYSTICK_SWITCH_STATES: Mutex<RefCell<[bool; 4]>> =
-    Mutex::new(RefCell::new([false, false, false, false]));
-pub struct JoystickSwitch {
-    joystick_direction: JoystickDirection,
-    switch_index: usize,
-}
+// only the digital joystick task reads the switches as a joystick, the analog build keeps the
+// mapping as part of the settings
+#[cfg(not(feature = "analog-joystick"))]
+use crate::{
+    channel::Sender,
+    input_switch::wait_until,
+    settings,
+    stepper::{StepperCommand, FULL_SPEED},
+    stuck_switch::{self, Switch},
+};
+#[cfg(not(feature = "analog-joystick"))]
+use futures::{select_biased, FutureExt};
 
-#[derive(Clone, Copy)]
+/**
+The four directions of the joystick, the physical switches are named after the direction they have
+with our default wiring
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum JoystickDirection {
     RIGHT,
     LEFT,
@@ -31,100 +42,263 @@ pub enum JoystickDirection {
     BACKWARD,
 }
 
-impl JoystickSwitch {
-    pub fn new(joystick_direction: JoystickDirection, switch_index: usize) -> Self {
+/**
+All eight directions of the joystick and its center position
+*/
+#[cfg(not(feature = "analog-joystick"))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum JoystickState {
+    Center,
+    Right,
+    ForwardRight,
+    Forward,
+    ForwardLeft,
+    Left,
+    BackwardLeft,
+    Backward,
+    BackwardRight,
+}
+
+#[cfg(not(feature = "analog-joystick"))]
+impl JoystickState {
+    /**
+    Resolves the pressed switches into one direction, opposing switches cancel each other out
+    */
+    pub fn from_switches(right: bool, left: bool, forward: bool, backward: bool) -> Self {
+        let x: i8 = match (right, left) {
+            (true, false) => 1,
+            (false, true) => -1,
+            _ => 0,
+        };
+        let y: i8 = match (forward, backward) {
+            (true, false) => 1,
+            (false, true) => -1,
+            _ => 0,
+        };
+
+        match (x, y) {
+            (1, 0) => JoystickState::Right,
+            (1, 1) => JoystickState::ForwardRight,
+            (0, 1) => JoystickState::Forward,
+            (-1, 1) => JoystickState::ForwardLeft,
+            (-1, 0) => JoystickState::Left,
+            (-1, -1) => JoystickState::BackwardLeft,
+            (0, -1) => JoystickState::Backward,
+            (1, -1) => JoystickState::BackwardRight,
+            _ => JoystickState::Center,
+        }
+    }
+
+    /**
+    Checks whether this joystick direction includes the given direction, diagonals include two
+    */
+    pub fn points(&self, direction: JoystickDirection) -> bool {
+        match direction {
+            JoystickDirection::RIGHT => matches!(
+                self,
+                JoystickState::Right | JoystickState::ForwardRight | JoystickState::BackwardRight
+            ),
+            JoystickDirection::LEFT => matches!(
+                self,
+                JoystickState::Left | JoystickState::ForwardLeft | JoystickState::BackwardLeft
+            ),
+            JoystickDirection::FORWARD => matches!(
+                self,
+                JoystickState::Forward | JoystickState::ForwardRight | JoystickState::ForwardLeft
+            ),
+            JoystickDirection::BACKWARD => matches!(
+                self,
+                JoystickState::Backward
+                    | JoystickState::BackwardRight
+                    | JoystickState::BackwardLeft
+            ),
+        }
+    }
+}
+
+/// all directions in the order of the pins 50, 51, 52 and 53
+pub const DIRECTIONS: [JoystickDirection; 4] = [
+    JoystickDirection::RIGHT,
+    JoystickDirection::LEFT,
+    JoystickDirection::FORWARD,
+    JoystickDirection::BACKWARD,
+];
+
+/**
+The gantry axes the joystick can drive
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GantryAxis {
+    X,
+    Y,
+}
+
+/**
+Mapping of the joystick to the gantry
+switches => logical direction of each physical switch, indexed by the switch
+motors => axis and motor direction of each logical direction, indexed by the direction
+*/
+#[derive(Clone, Copy)]
+pub struct JoystickMapping {
+    pub switches: [JoystickDirection; 4],
+    pub motors: [(GantryAxis, StepperDirection); 4],
+}
+
+impl Default for JoystickMapping {
+    /**
+    Mapping of our cabinet, the wiring of the pin configuration in main
+    */
+    fn default() -> Self {
         Self {
-            joystick_direction,
-            switch_index,
+            switches: DIRECTIONS,
+            motors: [
+                (GantryAxis::X, CounterClockWise),
+                (GantryAxis::X, ClockWise),
+                (GantryAxis::Y, CounterClockWise),
+                (GantryAxis::Y, ClockWise),
+            ],
         }
     }
+}
 
-    pub async fn wait_for(&mut self, desired_state: bool) {
-        poll_fn(|cx| {
-            if interrupt::free(|cs| {
-                return match self.joystick_direction {
-                    JoystickDirection::RIGHT => {
-                        desired_state == J_RIGHT.borrow(cs).take().unwrap().is_high()
-                    }
-                    JoystickDirection::LEFT => {
-                        desired_state == J_LEFT.borrow(cs).take().unwrap().is_high()
-                    }
-                    JoystickDirection::FORWARD => {
-                        desired_state == J_FORWARD.borrow(cs).take().unwrap().is_high()
-                    }
-                    JoystickDirection::BACKWARD => {
-                        desired_state == J_BACKWARD.borrow(cs).take().unwrap().is_high()
-                    }
+impl JoystickMapping {
+    /**
+    Inverts the motor direction of all directions that drive the given axis
+    */
+    pub fn invert(&mut self, axis: GantryAxis) {
+        for (motor_axis, direction) in self.motors.iter_mut() {
+            if *motor_axis == axis {
+                *direction = match direction {
+                    ClockWise => CounterClockWise,
+                    CounterClockWise => ClockWise,
+                    Idle => Idle,
                 };
-            }) {
-                Poll::Ready(())
-            } else {
-                interrupt::free(|cs| {
-                    let _ = JOYSTICK_SWITCH_TASKS
-                        .get(self.switch_index)
-                        .unwrap()
-                        .borrow(cs)
-                        .replace(cx.waker().task());
-                });
-                Poll::Pending
             }
-        })
-        .await
+        }
+    }
+
+    /**
+    Swaps the axes of all directions, for a joystick mounted rotated by 90 degrees
+    */
+    pub fn swap_axes(&mut self) {
+        for (axis, _) in self.motors.iter_mut() {
+            *axis = match axis {
+                GantryAxis::X => GantryAxis::Y,
+                GantryAxis::Y => GantryAxis::X,
+            };
+        }
+    }
+
+    /**
+    Gets the logical direction of a physical switch
+    */
+    #[cfg(not(feature = "analog-joystick"))]
+    pub fn direction(&self, switch: JoystickDirection) -> JoystickDirection {
+        self.switches[switch as usize]
+    }
+
+    /**
+    Gets the directions of the x- and y-axis motors for a joystick direction
+    */
+    #[cfg(not(feature = "analog-joystick"))]
+    pub fn axes(&self, state: JoystickState) -> (StepperDirection, StepperDirection) {
+        let mut x_direction = Idle;
+        let mut y_direction = Idle;
+        for direction in DIRECTIONS {
+            if state.points(direction) {
+                let (axis, motor_direction) = self.motors[direction as usize];
+                match axis {
+                    GantryAxis::X => x_direction = motor_direction,
+                    GantryAxis::Y => y_direction = motor_direction,
+                }
+            }
+        }
+        (x_direction, y_direction)
     }
 }
 
 /**
-Pin Change interrupt triggered if a Joy Stick switch has been triggered
+Gets the input switch of a physical switch of the joystick
 */
-#[avr_device::interrupt(atmega2560)]
-#[allow(non_snake_case)]
-fn PCINT0() {
-    // We don't actually need to create a critical section as AVR suppresses other interrupts during
-    // an interrupt
-    interrupt::free(|cs| {
-        let right_pin = J_RIGHT.borrow(cs).take().unwrap().downgrade();
-        let left_pin = J_LEFT.borrow(cs).take().unwrap().downgrade();
-        let forward_pin = J_FORWARD.borrow(cs).take().unwrap().downgrade();
-        let backward_pin = J_BACKWARD.borrow(cs).take().unwrap().downgrade();
-        let joystick_states = JOYSTICK_SWITCH_STATES.borrow(cs).borrow_mut();
-        let pins = [right_pin, left_pin, forward_pin, backward_pin];
-
-        for (index, switch_state) in joystick_states.iter().enumerate() {
-            if pins.get(index).unwrap().is_high() != *switch_state {
-                let joystick_task = JOYSTICK_SWITCH_TASKS
-                    .get(index)
-                    .unwrap()
-                    .borrow(cs)
-                    .replace(0xFFFF);
-                if joystick_task != 0xFFFF {
-                    wake_task(joystick_task)
-                }
-            }
+pub fn input(switch: JoystickDirection) -> InputSwitch {
+    match switch {
+        JoystickDirection::RIGHT => J_RIGHT,
+        JoystickDirection::LEFT => J_LEFT,
+        JoystickDirection::FORWARD => J_FORWARD,
+        JoystickDirection::BACKWARD => J_BACKWARD,
+    }
+}
+
+/**
+Samples all four switches into one logical joystick direction, stuck switches are left out
+*/
+#[cfg(not(feature = "analog-joystick"))]
+pub fn read_state(mapping: &JoystickMapping) -> JoystickState {
+    let mut pressed = [false; 4];
+    for switch in DIRECTIONS {
+        if !stuck_switch::is_ignored(Switch::Joystick(switch)) && input(switch).is_pressed() {
+            pressed[mapping.direction(switch) as usize] = true;
         }
-    });
+    }
+    let [right, left, forward, backward] = pressed;
+    JoystickState::from_switches(right, left, forward, backward)
 }
 
-pub async fn joystick_switch_task(
-    direction: JoystickDirection,
-    motor_sender: Sender<'_, StepperDirection>,
-) {
-    let (index, stepper_direction): (usize, StepperDirection) = match direction {
-        JoystickDirection::RIGHT => (0usize, CounterClockWise),
-        JoystickDirection::LEFT => (1usize, ClockWise),
-        JoystickDirection::FORWARD => (2usize, CounterClockWise),
-        JoystickDirection::BACKWARD => (3usize, ClockWise),
-    };
+/**
+Gets which switches are ignored as stuck, indexed by the switch
+*/
+#[cfg(not(feature = "analog-joystick"))]
+fn ignored_switches() -> [bool; 4] {
+    DIRECTIONS.map(|switch| stuck_switch::is_ignored(Switch::Joystick(switch)))
+}
+
+/**
+Waits until one of the switches changes for at least the debounce window, or until a switch is
+found stuck and has to be dropped from the direction
+*/
+#[cfg(not(feature = "analog-joystick"))]
+async fn wait_for_change() {
+    let ignored = ignored_switches();
+    let switches = DIRECTIONS.map(input);
+    let [right, left, forward, backward] = switches;
+    select_biased! {
+        _ = right.wait_for_change().fuse() => {},
+        _ = left.wait_for_change().fuse() => {},
+        _ = forward.wait_for_change().fuse() => {},
+        _ = backward.wait_for_change().fuse() => {},
+        _ = wait_until(&switches, || ignored_switches() != ignored).fuse() => {},
+    }
+}
 
-    let mut joystick_switch = JoystickSwitch::new(direction, index);
+/**
+Task that reads the joystick and drives both gantry axes
 
+A new direction only counts once a switch is stable for the debounce window, and only the axes whose
+direction changed get a new command
+*/
+#[cfg(not(feature = "analog-joystick"))]
+pub async fn joystick_task(
+    x_sender: Sender<'_, StepperCommand>,
+    y_sender: Sender<'_, StepperCommand>,
+) {
+    let mut state = JoystickState::Center;
+    let mut axes = (Idle, Idle);
     loop {
-        // wait for a low state
-        joystick_switch.wait_for(false).await;
-
-        motor_sender.send(stepper_direction);
+        let mapping = settings::current().joystick;
+        let new_state = read_state(&mapping);
+        if new_state != state {
+            let (x_direction, y_direction) = mapping.axes(new_state);
+            let (old_x_direction, old_y_direction) = axes;
+            if x_direction != old_x_direction {
+                x_sender.send(StepperCommand::new(x_direction, FULL_SPEED));
+            }
+            if y_direction != old_y_direction {
+                y_sender.send(StepperCommand::new(y_direction, FULL_SPEED));
+            }
+            state = new_state;
+            axes = (x_direction, y_direction);
+        }
 
-        // wait for a high state
-        joystick_switch.wait_for(true).await;
-        motor_sender.send(Idle);
+        wait_for_change().await;
     }
 }
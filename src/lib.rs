@@ -0,0 +1,12 @@
+//! This crate holds the logic of the claw machine that does not depend on the hardware
+//!
+//! The firmware uses these modules like any other crate. Since they neither touch registers nor
+//! interrupts, they also build for the host and are tested there.
+//!
+//! How to use:
+//! Run the tests on the host, e.g. `cargo test --lib --target x86_64-unknown-linux-gnu -Z build-std`
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod edges;
+pub mod state;
@@ -0,0 +1,193 @@
+//! This module holds the diagnostic output of the claw machine
+//!
+//! The macros error!, warn!, info! and debug! take a ufmt format string and its arguments. Every
+//! message is formatted into a line of the log buffer without waiting, so logging is fine within
+//! critical sections and interrupts. The log task drains the buffer to the console. A message that
+//! does not fit into the buffer is dropped and the log task reports the number of dropped messages.
+//!
+//! The levels are chosen at compile time with the cargo features log-error, log-warn, log-info and
+//! log-debug, each of them includes the levels above. Messages of a level that is not enabled are
+//! not compiled in at all, so their strings do not take any flash.
+//!
+//! How to use:
+//! Import the macros from this module and run the log task in every state
+
+use avr_device::interrupt;
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::task::Poll;
+use heapless::Deque;
+
+use crate::executor::{wake_task, ExtWaker};
+use crate::serial::{self, Line};
+use crate::Mutex;
+
+/// bytes of messages that can wait for the log task
+const BUFFER_SIZE: usize = 256;
+
+/// bytes the log task writes to the console at once
+const CHUNK_SIZE: usize = 16;
+
+static BUFFER: Mutex<RefCell<Deque<u8, BUFFER_SIZE>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// messages that have been dropped since the log task reported the last drop
+static DROPPED: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// the task draining the buffer, initialized with an invalid waker ID
+static LOG_TASK: Mutex<Cell<usize>> = Mutex::new(Cell::new(0xFFFF));
+
+/**
+All log levels, from the most to the least severe
+*/
+// the levels that are not enabled are never constructed
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/**
+Formats a message and puts it as a whole into the buffer, used by the macros
+*/
+pub fn write(level: Level, format: impl FnOnce(&mut Line) -> Result<(), Infallible>) {
+    let mut message = Line::default();
+    let _ = ufmt::uwrite!(&mut message, "{}: ", level.name());
+    let _ = format(&mut message);
+
+    interrupt::free(|cs| {
+        let mut buffer = BUFFER.borrow(cs).borrow_mut();
+        let line_len = message.as_bytes().len() + 2;
+        if buffer.capacity() - buffer.len() < line_len {
+            let dropped = DROPPED.borrow(cs);
+            dropped.set(dropped.get().saturating_add(1));
+        } else {
+            for byte in message.as_bytes().iter().chain(b"\r\n") {
+                let _ = buffer.push_back(*byte);
+            }
+        }
+
+        let log_task = LOG_TASK.borrow(cs).replace(0xFFFF);
+        if log_task != 0xFFFF {
+            wake_task(log_task)
+        }
+    })
+}
+
+/**
+Waits until the buffer holds messages or messages have been dropped
+*/
+async fn wait_for_messages() {
+    poll_fn(|cx| {
+        interrupt::free(|cs| {
+            if BUFFER.borrow(cs).borrow().is_empty() && DROPPED.borrow(cs).get() == 0 {
+                LOG_TASK.borrow(cs).set(cx.waker().task());
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+    })
+    .await
+}
+
+/**
+Task that drains the buffer to the console
+*/
+pub async fn log_task() {
+    loop {
+        wait_for_messages().await;
+
+        let mut chunk: heapless::Vec<u8, CHUNK_SIZE> = heapless::Vec::new();
+        interrupt::free(|cs| {
+            let mut buffer = BUFFER.borrow(cs).borrow_mut();
+            while !chunk.is_full() {
+                match buffer.pop_front() {
+                    Some(byte) => {
+                        let _ = chunk.push(byte);
+                    }
+                    None => break,
+                }
+            }
+        });
+        serial::write(&chunk).await;
+
+        let dropped = interrupt::free(|cs| DROPPED.borrow(cs).replace(0));
+        if dropped > 0 {
+            serial::print(|line| ufmt::uwrite!(line, "log: {} messages dropped", dropped)).await;
+        }
+    }
+}
+
+/// logs a message at the error level if the feature log-error is enabled
+macro_rules! error {
+    ($format:tt $(, $arg:expr)* $(,)?) => {{
+        #[cfg(feature = "log-error")]
+        $crate::log::write($crate::log::Level::Error, |message| {
+            ufmt::uwrite!(message, $format $(, $arg)*)
+        });
+        #[cfg(not(feature = "log-error"))]
+        {
+            $(let _ = &$arg;)*
+        }
+    }};
+}
+
+/// logs a message at the warn level if the feature log-warn is enabled
+macro_rules! warn {
+    ($format:tt $(, $arg:expr)* $(,)?) => {{
+        #[cfg(feature = "log-warn")]
+        $crate::log::write($crate::log::Level::Warn, |message| {
+            ufmt::uwrite!(message, $format $(, $arg)*)
+        });
+        #[cfg(not(feature = "log-warn"))]
+        {
+            $(let _ = &$arg;)*
+        }
+    }};
+}
+
+/// logs a message at the info level if the feature log-info is enabled
+macro_rules! info {
+    ($format:tt $(, $arg:expr)* $(,)?) => {{
+        #[cfg(feature = "log-info")]
+        $crate::log::write($crate::log::Level::Info, |message| {
+            ufmt::uwrite!(message, $format $(, $arg)*)
+        });
+        #[cfg(not(feature = "log-info"))]
+        {
+            $(let _ = &$arg;)*
+        }
+    }};
+}
+
+/// logs a message at the debug level if the feature log-debug is enabled
+macro_rules! debug {
+    ($format:tt $(, $arg:expr)* $(,)?) => {{
+        #[cfg(feature = "log-debug")]
+        $crate::log::write($crate::log::Level::Debug, |message| {
+            ufmt::uwrite!(message, $format $(, $arg)*)
+        });
+        #[cfg(not(feature = "log-debug"))]
+        {
+            $(let _ = &$arg;)*
+        }
+    }};
+}
+
+#[allow(unused_imports)]
+pub(crate) use {debug, error, info, warn};
@@ -6,35 +6,49 @@
 #![no_main]
 #![feature(abi_avr_interrupt)]
 #![feature(future_join)]
+#![feature(inline_const)]
 
+mod attract;
 mod button;
+// the calibration of the stick is part of the settings of every build, the stick is only read with
+// the analog-joystick feature
+#[cfg_attr(not(feature = "analog-joystick"), allow(dead_code))]
+mod analog_joystick;
 mod channel;
+mod credit;
+mod debounce;
 mod executor;
+mod fault;
 mod game;
+mod grab;
+mod grip;
+mod input_switch;
 mod joystick;
-mod limit_switch;
+mod log;
+mod play_timer;
+mod prize_chute;
+mod ramp;
+mod serial;
+mod servo;
+mod settings;
+mod shell;
+mod stats;
 mod stepper;
+mod store;
+mod stuck_switch;
 mod timer;
 
 #[allow(unused_imports)]
 use panic_halt as _;
 
-use crate::game::{Game, GameState};
+use crate::game::{Game, Machine};
+use crate::input_switch::{InputSwitch, PcintBank};
+use crate::servo::Servo;
+use crate::stepper::Axis;
 use crate::timer::{GenericTicker, PrecisionTicker};
-use arduino_hal::hal::port::Dynamic;
-use arduino_hal::port::mode::{Input, PullUp};
-use arduino_hal::port::Pin;
-use arduino_hal::simple_pwm::Prescaler::Prescale64;
-use arduino_hal::simple_pwm::{IntoPwmPin, Timer3Pwm};
 use avr_device::interrupt;
-use core::cell::{Cell, RefCell};
-use core::pin::pin;
-use crate::channel::Channel;
-use crate::joystick::{joystick_switch_task, JoystickDirection};
-use crate::stepper::{x_gantry, y_gantry, StepperDirection};
 
 type Mutex<T> = interrupt::Mutex<T>;
-type Console = arduino_hal::hal::usart::Usart0<arduino_hal::DefaultClock>;
 
 /*
 PIN Configuration:
@@ -47,6 +61,11 @@ INPUT:
         2. Left: 51 PCINT2
         3. Forward: 52 PCINT1
         4. Backward: 53 PCINT0
+        (other wirings are mapped with the map command of the shell)
+
+    Analog Thumbstick (cargo feature analog-joystick, replaces the Joystick)
+        1. X-Axis: A0
+        2. Y-Axis: A1
 
     Play Button (PCINT1)
         1. Start Button: 15 PCINT9
@@ -57,6 +76,12 @@ INPUT:
         2. Y Limit (forward/backward): A9 PCINT17
         3. Z Limit (Pulley up/down): A10 PCINT18
 
+    Prize Chute Sensor (PCINT2)
+        1. Chute: A11 PCINT19
+
+    Coin Acceptor (INT4)
+        1. Coin: 2 INT4
+
 OUTPUT:
     Stepper Motor
         1. X-Pulse: 22
@@ -78,36 +103,37 @@ OUTPUT:
 
 // Joy stick Pins
 /// Joystick Right input Pin
-static J_RIGHT: Mutex<RefCell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(RefCell::new(None));
+const J_RIGHT: InputSwitch = InputSwitch::new(PcintBank::Pcint0, 3);
 
 /// Joystick Left input Pin
-static J_LEFT: Mutex<RefCell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(RefCell::new(None));
+const J_LEFT: InputSwitch = InputSwitch::new(PcintBank::Pcint0, 2);
 
 /// Joystick Forward input Pin
-static J_FORWARD: Mutex<RefCell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(RefCell::new(None));
+const J_FORWARD: InputSwitch = InputSwitch::new(PcintBank::Pcint0, 1);
 
 /// Joystick Backward input Pin
-static J_BACKWARD: Mutex<RefCell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(RefCell::new(None));
+const J_BACKWARD: InputSwitch = InputSwitch::new(PcintBank::Pcint0, 0);
 
 // Button Pins
 /// UI Button start input Pin
-static B_START: Mutex<Cell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(Cell::new(None));
+const B_START: InputSwitch = InputSwitch::new(PcintBank::Pcint1, 1);
 
 /// UI Button end input Pin
-static B_END: Mutex<Cell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(Cell::new(None));
+const B_END: InputSwitch = InputSwitch::new(PcintBank::Pcint1, 2);
 
 // Limit switch Pins
 /// Limit switch X
-static X_LIMIT: Mutex<Cell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(Cell::new(None));
+const X_LIMIT: InputSwitch = InputSwitch::new(PcintBank::Pcint2, 0);
 
 /// Limit switch Y
-static Y_LIMIT: Mutex<Cell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(Cell::new(None));
+const Y_LIMIT: InputSwitch = InputSwitch::new(PcintBank::Pcint2, 1);
 
 /// Limit switch Z
-static Z_LIMIT: Mutex<Cell<Option<Pin<Input<PullUp>, Dynamic>>>> = Mutex::new(Cell::new(None));
+const Z_LIMIT: InputSwitch = InputSwitch::new(PcintBank::Pcint2, 2);
 
-/// Create a console that can be used safely within an interrupt
-static CONSOLE: Mutex<RefCell<Option<Console>>> = Mutex::new(RefCell::new(None));
+// Prize chute Pin
+/// Prize chute sensor, low while a prize passes
+const P_CHUTE: InputSwitch = InputSwitch::new(PcintBank::Pcint2, 3);
 
 /**
 Entrypoint for the Program
@@ -118,153 +144,87 @@ fn main() -> ! {
     let pins = arduino_hal::pins!(dp);
 
 
-    // create a serial connection with the console output
-    let serial = arduino_hal::default_serial!(dp, pins, 57600);
+    // create a serial connection with the console output, 57600 baud
+    serial::init(dp.USART0, pins.d0, pins.d1.into_output());
 
-    let mut x_stepper_pulse = pins.d22.into_output();
+    // load the statistics and the settings of the operator, a fault on an invalid record is logged
+    // and counted in the statistics
+    store::init(arduino_hal::Eeprom::new(dp.EEPROM));
+    stats::load();
+    settings::load();
+    let settings = settings::current();
 
-    let mut x_stepper_direction = pins.d23.into_output();
+    let x_axis = Axis::new(
+        pins.d22.into_output().downgrade(),
+        pins.d23.into_output().downgrade(),
+        settings.stepper.max_x_steps,
+    );
 
-    let mut y_stepper_pulse = pins.d24.into_output();
+    let y_axis = Axis::with_mirrored(
+        pins.d24.into_output().downgrade(),
+        pins.d25.into_output().downgrade(),
+        pins.d26.into_output().downgrade(),
+        pins.d27.into_output().downgrade(),
+        settings.stepper.max_y_steps,
+    );
 
-    let mut y_stepper_direction = pins.d25.into_output();
+    let z_axis = Axis::new(
+        pins.d28.into_output().downgrade(),
+        pins.d29.into_output().downgrade(),
+        settings.stepper.max_z_steps,
+    );
 
-    let mut y_stepper_pulse_inverted = pins.d26.into_output();
+    let start_led = pins.d30.into_output().downgrade();
 
-    let mut y_stepper_direction_inverted = pins.d27.into_output();
+    let end_led = pins.d31.into_output().downgrade();
 
-    let mut z_stepper_pulse = pins.d28.into_output();
+    #[cfg(feature = "analog-joystick")]
+    let mut adc = arduino_hal::Adc::new(dp.ADC, Default::default());
+    #[cfg(feature = "analog-joystick")]
+    let x_stick = pins.a0.into_analog_input(&mut adc);
+    #[cfg(feature = "analog-joystick")]
+    let y_stick = pins.a1.into_analog_input(&mut adc);
 
-    let mut  z_stepper_direction = pins.d29.into_output();
+    let claw = Servo::new(dp.TC3, pins.d5.into_output(), settings.servo);
 
-    let mut start_led = pins.d30.into_output();
+    // hand the input pins over to the input layer
+    J_RIGHT.attach(pins.d50.into_pull_up_input().downgrade());
+    J_LEFT.attach(pins.d51.into_pull_up_input().downgrade());
+    J_FORWARD.attach(pins.d52.into_pull_up_input().downgrade());
+    J_BACKWARD.attach(pins.d53.into_pull_up_input().downgrade());
 
-    let mut end_led = pins.d31.into_output();
+    B_START.attach(pins.d15.into_pull_up_input().downgrade());
+    B_END.attach(pins.d14.into_pull_up_input().downgrade());
 
-    let timer3 = Timer3Pwm::new(dp.TC3, Prescale64);
+    X_LIMIT.attach(pins.a8.into_pull_up_input().downgrade());
+    Y_LIMIT.attach(pins.a9.into_pull_up_input().downgrade());
+    Z_LIMIT.attach(pins.a10.into_pull_up_input().downgrade());
 
-    #[allow(unused_mut)]
-    let mut claw_pwm = pins.d5.into_output().into_pwm(&timer3);
+    P_CHUTE.attach(pins.a11.into_pull_up_input().downgrade());
 
-    // even tough interrupts are not enabled yet still have to create critical section for mutex
-    // set all static variables
-    interrupt::free(|cs| {
-        // set console
-        *CONSOLE.borrow(cs).borrow_mut() = Some(serial);
+    // count the coins with an external interrupt, it stays enabled in every state
+    credit::init(&dp.EXINT, pins.d2.into_pull_up_input());
 
-        // set input pins
-        *J_RIGHT.borrow(cs).borrow_mut() = Some(pins.d50.into_pull_up_input().downgrade());
-        *J_LEFT.borrow(cs).borrow_mut() = Some(pins.d51.into_pull_up_input().downgrade());
-        *J_FORWARD.borrow(cs).borrow_mut() = Some(pins.d52.into_pull_up_input().downgrade());
-        *J_BACKWARD.borrow(cs).borrow_mut() = Some(pins.d53.into_pull_up_input().downgrade());
-
-    });
     // initialize static Tickers
     PrecisionTicker::init(dp.TC0);
     GenericTicker::init(dp.TC1);
     // enable interrupts for the device
     unsafe { interrupt::enable() };
 
-    let exint = dp.EXINT;
-
-    let mut game_state = GameState::IDLE;
-
-
-    // game loop
-    loop {
-        match game_state {
-            GameState::IDLE => {
-                // enable limit switch interrupts
-                exint.pcicr.write(|w| unsafe { w.bits(0b100) });
-                exint.pcmsk2.write(|w| w.bits(0b00000111));
-
-                let reset_task = pin!(reset_game());
-                let blink_led_task = pin!(blink_led());
-                executor::run_task(&mut [reset_task, blink_led_task]);
-
-                // enable UI button interrupts and disable limit switch interrupts
-                exint.pcicr.write(|w| unsafe { w.bits(0b010) });
-                exint.pcmsk1.write(|w| w.bits(0b00000010));
-
-                // task that waits for user to press green button
-                let wait_for_start_task = pin!(wait_for_start());
-                executor::run_task(&mut [wait_for_start_task]);
-
-
-                game_state = GameState::RUNNING;
-            }
-            GameState::RUNNING => {
-                // enable all interrupts except limit switches
-                exint.pcicr.write(|w| unsafe { w.bits(0b011) });
-                // Joystick pc interrupt pins
-                exint.pcmsk0.write(|w| w.bits(0b00001111));
-                // end button interrupt pin
-                exint.pcmsk1.write(|w| w.bits(0b00000100));
-
-
-                // channels for both x-and y-axis
-                let x_channel: Channel<StepperDirection> = Channel::new();
-                let y_channel: Channel<StepperDirection> = Channel::new();
-
-                let x_gantry_task = pin!(x_gantry(
-                    x_channel.get_receiver(),
-                    &mut x_stepper_direction,
-                    &mut x_stepper_pulse
-                ));
-
-                let y_gantry_task = pin!(y_gantry(
-                    y_channel.get_receiver(),
-                    &mut y_stepper_direction,
-                    &mut y_stepper_pulse,
-                    &mut y_stepper_direction_inverted,
-                    &mut y_stepper_pulse_inverted,
-                ));
-
-                let joystick_right_task = pin!(joystick_switch_task(
-                        JoystickDirection::RIGHT,
-                        x_channel.get_sender()
-                    ));
-                let joystick_left_task = pin!(joystick_switch_task(
-                        JoystickDirection::LEFT,
-                        x_channel.get_sender()
-                    ));
-                let joystick_forward_task = pin!(joystick_switch_task(
-                        JoystickDirection::FORWARD,
-                        y_channel.get_sender()
-                    ));
-                let joystick_backward_task = pin!(joystick_switch_task(
-                        JoystickDirection::BACKWARD,
-                        y_channel.get_sender()
-                    ));
-
-                executor::run_task(&mut [joystick_right_task,joystick_left_task,joystick_forward_task,joystick_backward_task, x_gantry_task, y_gantry_task ])
-
-            },
-            GameState::FINISHED => {
-            }
-        }
-    }
-}
-
-async fn reset_game(
-
-) {
-
-}
-
-async fn wait_for_start(
-
-) {
-
+    let machine = Machine {
+        x_axis,
+        y_axis,
+        z_axis,
+        claw,
+        start_led,
+        end_led,
+        #[cfg(feature = "analog-joystick")]
+        adc,
+        #[cfg(feature = "analog-joystick")]
+        x_stick,
+        #[cfg(feature = "analog-joystick")]
+        y_stick,
+    };
+
+    Game::new(dp.EXINT, machine).run()
 }
-async fn wait_for_end(
-
-) {
-
-}
-
-async fn blink_led(
-
-) {
-
-}
\ No newline at end of file
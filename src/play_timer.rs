@@ -0,0 +1,94 @@
+//! This module limits the time a player has for moving the claw
+//!
+//! The play timer starts together with the game and runs on the generic timer. If the player does
+//! not press the end button in time, the timer ends the game and the claw grabs automatically.
+//!
+//! The remaining time is kept in a static, so LEDs or a display can show it. The end button LED is
+//! lit while the timer runs and blinks during the last seconds.
+//!
+//! How to use:
+//! Run the play timer task alongside the tasks of a running game, once the executor loop breaks
+//! stop the timer and check whether the time ran out
+
+use arduino_hal::hal::port::Dynamic;
+use arduino_hal::port::mode::Output;
+use arduino_hal::port::Pin;
+use avr_device::interrupt;
+use core::cell::Cell;
+
+use crate::executor::wake_task;
+use crate::settings;
+use crate::timer::{delay_ms, delay_s, s_to_generic_ticks, GenericTicker};
+use crate::Mutex;
+
+/// Play time of the default settings
+pub const DEFAULT_PLAY_TIME_S: u8 = 30;
+
+/// the end button LED starts blinking once fewer seconds than this are left
+const WARNING_S: u8 = 5;
+
+/// generic tick count at which the running game ends, None while no game runs
+static DEADLINE: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// true if the last game ended because the time ran out
+static EXPIRED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/**
+Gets the seconds the player has left, rounded up
+
+RETURNS: None while no game runs
+*/
+pub fn remaining_s() -> Option<u8> {
+    let deadline = interrupt::free(|cs| DEADLINE.borrow(cs).get())?;
+    let remaining_ticks = deadline.saturating_sub(GenericTicker::now());
+    let one_second = s_to_generic_ticks(1);
+    Some(((remaining_ticks + one_second - 1) / one_second) as u8)
+}
+
+/**
+Checks whether the last game ended because the time ran out
+*/
+pub fn expired() -> bool {
+    interrupt::free(|cs| EXPIRED.borrow(cs).get())
+}
+
+/**
+Stops the timer of the running game, the remaining time is gone afterwards
+*/
+pub fn stop() {
+    interrupt::free(|cs| DEADLINE.borrow(cs).set(None));
+}
+
+/**
+Task that starts the timer and ends the game once the play time is over
+*/
+pub async fn play_timer_task(led: &mut Pin<Output, Dynamic>) {
+    let play_time = s_to_generic_ticks(settings::current().play_time_s);
+    interrupt::free(|cs| {
+        EXPIRED.borrow(cs).set(false);
+        DEADLINE
+            .borrow(cs)
+            .set(Some(GenericTicker::now() + play_time));
+    });
+
+    led.set_high();
+    while let Some(remaining) = remaining_s() {
+        if remaining == 0 {
+            break;
+        }
+        if remaining <= WARNING_S {
+            // blink twice a second during the last seconds
+            for _ in 0..4 {
+                led.toggle();
+                delay_ms(250).await;
+            }
+        } else {
+            delay_s(1).await;
+        }
+    }
+    led.set_low();
+
+    interrupt::free(|cs| EXPIRED.borrow(cs).set(true));
+    // break the executor loop to advance to finished state
+    wake_task(0xFFFF);
+}
@@ -0,0 +1,62 @@
+//! This module holds the sensor in the prize chute
+//!
+//! An IR break-beam or a microswitch in the chute pulls its pin low while a prize falls through.
+//! The sensor shares the pin change bank of the limit switches (PCINT19 on pin A11), so the PCINT2
+//! interrupt forwards every falling edge of the sensor to this module.
+//!
+//! A falling prize only interrupts the beam for a few milliseconds, thus the interrupt latches the
+//! detection and the grab sequence waits for the latch instead of sampling the pin.
+//!
+//! How to use:
+//! Arm the sensor before the claw opens and wait for the prize with a timeout
+
+use avr_device::interrupt;
+use core::cell::Cell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use crate::executor::{wake_task, ExtWaker};
+use crate::Mutex;
+
+/// true once a prize has passed the sensor since the sensor has been armed
+static PRIZE_DETECTED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// the task waiting for a prize, initialized with an invalid waker ID
+static PRIZE_TASK: Mutex<Cell<usize>> = Mutex::new(Cell::new(0xFFFF));
+
+/**
+Clears the latched detection, any prize detected before does not count
+*/
+pub fn arm() {
+    interrupt::free(|cs| PRIZE_DETECTED.borrow(cs).set(false));
+}
+
+/**
+Waits until a prize has passed the sensor since it has been armed
+*/
+pub async fn wait_for_prize() {
+    poll_fn(|cx| {
+        interrupt::free(|cs| {
+            if PRIZE_DETECTED.borrow(cs).get() {
+                Poll::Ready(())
+            } else {
+                PRIZE_TASK.borrow(cs).set(cx.waker().task());
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/**
+Called by the PCINT2 interrupt once a prize blocks the sensor
+*/
+pub(crate) fn on_blocked() {
+    interrupt::free(|cs| {
+        PRIZE_DETECTED.borrow(cs).set(true);
+        let prize_task = PRIZE_TASK.borrow(cs).replace(0xFFFF);
+        if prize_task != 0xFFFF {
+            wake_task(prize_task)
+        }
+    })
+}
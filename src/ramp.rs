@@ -0,0 +1,131 @@
+//! This module ramps up the speed of a gantry axis while the joystick is held
+//!
+//! The digital joystick only knows full speed, which makes precise positioning of the claw hard. The
+//! ramp sits between the joystick task and the gantry task of an axis, it starts every new direction
+//! slow and speeds up the longer the direction is held. A new direction starts the ramp all over.
+//!
+//! The speed of the incoming command is the ceiling of the ramp, so a proportional source would
+//! still be scaled down.
+
+use crate::stepper::FULL_SPEED;
+
+// the curve is part of the settings of every build, only the digital joystick is ramped
+#[cfg(not(feature = "analog-joystick"))]
+use {
+    crate::channel::{Receiver, Sender},
+    crate::stepper::StepperCommand,
+    crate::stepper::StepperDirection::Idle,
+    crate::timer::{delay_ms, PrecisionTicker},
+    futures::{select_biased, FutureExt},
+};
+
+/**
+Shapes the speed of the ramp can follow
+linear => the speed grows constantly
+quadratic => the speed grows slow at first, giving more time for precise positioning
+*/
+#[derive(Clone, Copy)]
+pub enum RampShape {
+    Linear,
+    Quadratic,
+}
+
+/**
+Parameters of the speed ramp
+start speed => speed right after a direction has been pressed
+max speed => speed after the direction has been held for the ramp duration
+ramp ms => time it takes to reach the max speed
+interval ms => time between two speed updates, every update interrupts the current step
+*/
+#[derive(Clone, Copy)]
+pub struct RampCurve {
+    pub start_speed: u8,
+    pub max_speed: u8,
+    pub ramp_ms: u16,
+    pub interval_ms: u16,
+    pub shape: RampShape,
+}
+
+impl Default for RampCurve {
+    fn default() -> Self {
+        Self {
+            start_speed: 40,
+            max_speed: FULL_SPEED,
+            ramp_ms: 1500,
+            interval_ms: 100,
+            shape: RampShape::Quadratic,
+        }
+    }
+}
+
+#[cfg(not(feature = "analog-joystick"))]
+impl RampCurve {
+    /**
+    Gets the speed after the direction has been held for the given time
+    */
+    fn speed(&self, held_ms: u64) -> u8 {
+        let ramp_ms = self.ramp_ms.max(1) as u64;
+        let held_ms = held_ms.min(ramp_ms);
+        // progress in per mille
+        let progress = held_ms * 1000 / ramp_ms;
+        let progress = match self.shape {
+            RampShape::Linear => progress,
+            RampShape::Quadratic => progress * progress / 1000,
+        };
+
+        let start = self.start_speed as u64;
+        let max = self.max_speed.max(self.start_speed) as u64;
+        (start + (max - start) * progress / 1000) as u8
+    }
+}
+
+/**
+Scales the speed of a command down to the speed of the ramp
+*/
+#[cfg(not(feature = "analog-joystick"))]
+fn scale(command: StepperCommand, speed: u8) -> StepperCommand {
+    let speed = speed as u16 * command.speed as u16 / FULL_SPEED as u16;
+    StepperCommand::new(command.direction, speed as u8)
+}
+
+/**
+Task that forwards the commands of the joystick to the gantry and ramps up their speed
+*/
+#[cfg(not(feature = "analog-joystick"))]
+pub async fn speed_ramp_task(
+    mut receiver: Receiver<'_, StepperCommand>,
+    sender: Sender<'_, StepperCommand>,
+    curve: RampCurve,
+) {
+    let mut command = StepperCommand::IDLE;
+    let mut held_since = 0;
+    let mut speed = 0;
+    loop {
+        let new_command = if command.direction == Idle || speed == curve.speed(u64::MAX) {
+            // nothing to ramp, wait for the next command
+            command = receiver.receive().await;
+            true
+        } else {
+            select_biased! {
+                new_command = receiver.receive().fuse() => {
+                    command = new_command;
+                    true
+                },
+                _ = delay_ms(curve.interval_ms).fuse() => false,
+            }
+        };
+
+        if new_command {
+            // a new command restarts the ramp
+            held_since = PrecisionTicker::now_ms();
+            speed = curve.speed(0);
+            sender.send(scale(command, speed));
+        } else {
+            let new_speed = curve.speed(PrecisionTicker::now_ms() - held_since);
+            if new_speed != speed {
+                speed = new_speed;
+                sender.send(scale(command, speed));
+            }
+        }
+    }
+}
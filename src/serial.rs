@@ -0,0 +1,218 @@
+//! This module holds the interrupt driven driver of the serial console on USART0
+//!
+//! Received bytes are put into a ring buffer by the RX complete interrupt, bytes to send are taken
+//! from a ring buffer by the data register empty interrupt. Tasks await bytes and room in the
+//! buffers instead of busy waiting on the USART, so the executor keeps running the other tasks.
+//!
+//! Several tasks may wait for room in the transmit buffer at once, the interrupt wakes all of them.
+//! Received bytes are dropped while the receive buffer is full.
+//!
+//! How to use:
+//! Initialize the console during setup, format output into a line and await read and write within
+//! tasks
+
+use arduino_hal::hal::port::{PE0, PE1};
+use arduino_hal::port::mode::{Floating, Input, Output};
+use arduino_hal::port::Pin;
+use avr_device::atmega2560::USART0;
+use avr_device::interrupt;
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::future::poll_fn;
+use core::task::Poll;
+use heapless::{Deque, String};
+
+use crate::executor::{wake_task, ExtWaker};
+use crate::Mutex;
+
+/// baud rate of the console
+pub const BAUD_RATE: u32 = 57600;
+
+/// baud rate register at double speed, rounded to the closest rate the 16 MHz clock allows
+const UBRR: u16 = ((16_000_000 + 4 * BAUD_RATE) / (8 * BAUD_RATE) - 1) as u16;
+
+/// bytes that can be received before a task reads them
+const RX_SIZE: usize = 64;
+
+/// bytes that can wait for their transmission
+const TX_SIZE: usize = 128;
+
+/// the longest line that can be formatted, longer lines are cut off
+const LINE_SIZE: usize = 64;
+
+/// The USART, None until the console has been initialized
+static USART: Mutex<RefCell<Option<USART0>>> = Mutex::new(RefCell::new(None));
+
+static RX_BUFFER: Mutex<RefCell<Deque<u8, RX_SIZE>>> = Mutex::new(RefCell::new(Deque::new()));
+
+static TX_BUFFER: Mutex<RefCell<Deque<u8, TX_SIZE>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// the task waiting for a received byte, initialized with an invalid waker ID
+static RX_TASK: Mutex<Cell<usize>> = Mutex::new(Cell::new(0xFFFF));
+
+/// a bit for every task waiting for room in the transmit buffer, the executor runs at most 16 tasks
+static TX_TASKS: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+/**
+A single line that is formatted before it is written, longer lines are cut off
+*/
+#[derive(Default)]
+pub struct Line {
+    text: String<LINE_SIZE>,
+}
+
+impl Line {
+    pub fn as_bytes(&self) -> &[u8] {
+        self.text.as_bytes()
+    }
+}
+
+impl ufmt::uWrite for Line {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        for c in s.chars() {
+            if self.text.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+Configures USART0 with 8N1 at the baud rate and enables the RX complete interrupt
+
+The USART takes over both pins once it is enabled
+*/
+pub fn init(usart: USART0, _rx: Pin<Input<Floating>, PE0>, _tx: Pin<Output, PE1>) {
+    usart.ubrr0.write(|w| w.bits(UBRR));
+    usart.ucsr0a.write(|w| w.u2x0().set_bit());
+    usart.ucsr0c.write(|w| {
+        w.umsel0()
+            .usart_async()
+            .ucsz0()
+            .chr8()
+            .usbs0()
+            .stop1()
+            .upm0()
+            .disabled()
+    });
+    usart
+        .ucsr0b
+        .write(|w| w.txen0().set_bit().rxen0().set_bit().rxcie0().set_bit());
+
+    interrupt::free(|cs| *USART.borrow(cs).borrow_mut() = Some(usart));
+}
+
+/**
+Puts a byte into the transmit buffer and enables the data register empty interrupt
+
+RETURNS: false if the buffer is full
+*/
+fn try_push(cs: interrupt::CriticalSection, byte: u8) -> bool {
+    let usart = USART.borrow(cs).borrow();
+    // bytes written before the console has been initialized are lost
+    let Some(usart) = usart.as_ref() else {
+        return true;
+    };
+    if TX_BUFFER.borrow(cs).borrow_mut().push_back(byte).is_err() {
+        return false;
+    }
+    usart.ucsr0b.modify(|_, w| w.udrie0().set_bit());
+    true
+}
+
+/**
+Waits until a byte has been received
+*/
+pub async fn read() -> u8 {
+    poll_fn(|cx| {
+        interrupt::free(|cs| match RX_BUFFER.borrow(cs).borrow_mut().pop_front() {
+            Some(byte) => Poll::Ready(byte),
+            None => {
+                RX_TASK.borrow(cs).set(cx.waker().task());
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/**
+Writes the bytes, waits for room in the transmit buffer instead of blocking
+*/
+pub async fn write(bytes: &[u8]) {
+    for byte in bytes {
+        poll_fn(|cx| {
+            interrupt::free(|cs| {
+                if try_push(cs, *byte) {
+                    Poll::Ready(())
+                } else {
+                    let tx_tasks = TX_TASKS.borrow(cs);
+                    tx_tasks.set(tx_tasks.get() | 1 << cx.waker().task());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+/**
+Formats a line and writes it, waits for room in the transmit buffer instead of blocking
+*/
+pub async fn print(format: impl FnOnce(&mut Line) -> Result<(), Infallible>) {
+    let mut line = Line::default();
+    let _ = format(&mut line);
+    write(line.as_bytes()).await;
+    write(b"\r\n").await;
+}
+
+fn wake(task: &Mutex<Cell<usize>>, cs: interrupt::CriticalSection) {
+    let task = task.borrow(cs).replace(0xFFFF);
+    if task != 0xFFFF {
+        wake_task(task)
+    }
+}
+
+/**
+Interrupt triggered once a byte has been received
+*/
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn USART0_RX() {
+    interrupt::free(|cs| {
+        if let Some(usart) = USART.borrow(cs).borrow().as_ref() {
+            // reading the data register clears the interrupt, even if the byte has to be dropped
+            let byte = usart.udr0.read().bits();
+            let _ = RX_BUFFER.borrow(cs).borrow_mut().push_back(byte);
+            wake(&RX_TASK, cs);
+        }
+    })
+}
+
+/**
+Interrupt triggered while the data register is empty and the data register empty interrupt is
+enabled
+*/
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn USART0_UDRE() {
+    interrupt::free(|cs| {
+        if let Some(usart) = USART.borrow(cs).borrow().as_ref() {
+            match TX_BUFFER.borrow(cs).borrow_mut().pop_front() {
+                Some(byte) => usart.udr0.write(|w| w.bits(byte)),
+                // nothing left to send, the interrupt would fire forever otherwise
+                None => usart.ucsr0b.modify(|_, w| w.udrie0().clear_bit()),
+            }
+            // every waiting task tries again, the ones that find no room wait again
+            let tx_tasks = TX_TASKS.borrow(cs).replace(0);
+            for task in 0..16 {
+                if tx_tasks & (1 << task) != 0 {
+                    wake_task(task)
+                }
+            }
+        }
+    })
+}
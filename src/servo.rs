@@ -0,0 +1,178 @@
+//! This module abstracts the servo motor of the claw
+//!
+//! A hobby servo expects a pulse every 20 ms (50 Hz) and the width of that pulse, usually somewhere
+//! between 1 ms and 2 ms, encodes the angle. The SimplePWM abstraction of the HAL only offers the
+//! 8 bit PWM modes with fixed prescalers which can not produce such a frame, so the servo configures
+//! TC3 itself.
+//!
+//! TC3 runs in fast PWM mode 14 where ICR3 holds TOP and OCR3A the pulse width. With a prescaler
+//! of 8 the counter runs at 2 MHz, so one tick equals 0.5 microseconds and a TOP of 39'999 results
+//! in the 20 ms frame. The pulse is put out on OC3A which is pin 5 on the Mega2560.
+//!
+//! Setting an angle makes the servo snap to it as fast as it can. To open and close the claw
+//! smoothly `move_to_angle` interpolates between the current and the target angle, one step every
+//! frame, following an easing curve.
+//!
+//! How to use:
+//! Create a Servo with its calibration and set either an angle or a raw pulse width, or await a
+//! smooth movement
+
+use arduino_hal::hal::port::PE3;
+use arduino_hal::port::mode::Output;
+use arduino_hal::port::Pin;
+use avr_device::atmega2560::TC3;
+
+use crate::timer::delay_ms;
+
+/// Timer ticks per microsecond with a prescaler of 8
+const TICKS_PER_US: u16 = 2;
+
+/// TOP of the counter register, results in a 20 ms frame
+const FRAME_TOP: u16 = 39999;
+
+/// Largest angle a servo can be set to
+pub const MAX_ANGLE: u8 = 180;
+
+/// Length of one frame in milliseconds, the servo can not follow faster updates
+const FRAME_MS: u16 = 20;
+
+/// Progress of a movement is expressed in per mille to stay in integer arithmetics
+const PROGRESS_MAX: u32 = 1000;
+
+/**
+Curves a smooth movement can follow
+linear => constant speed from start to end
+ease in out => starts slow, speeds up and slows down again before reaching the target
+*/
+#[derive(Clone, Copy)]
+pub enum Easing {
+    Linear,
+    EaseInOut,
+}
+
+impl Easing {
+    /**
+    Maps the linear progress of a movement onto the eased progress
+
+    Both progresses are in per mille
+    */
+    fn apply(&self, progress: u32) -> u32 {
+        match self {
+            Easing::Linear => progress,
+            Easing::EaseInOut => {
+                // smoothstep 3p^2 - 2p^3 scaled to PROGRESS_MAX
+                let p = progress.min(PROGRESS_MAX);
+                (3 * p * p * PROGRESS_MAX - 2 * p * p * p) / (PROGRESS_MAX * PROGRESS_MAX)
+            }
+        }
+    }
+}
+
+/**
+Pulse widths in microseconds that correspond to 0 and MAX_ANGLE degrees
+
+Every servo differs slightly, so the range has to be calibrated per servo
+*/
+#[derive(Clone, Copy)]
+pub struct ServoCalibration {
+    pub min_pulse_us: u16,
+    pub max_pulse_us: u16,
+}
+
+impl ServoCalibration {
+    pub const fn new(min_pulse_us: u16, max_pulse_us: u16) -> Self {
+        Self {
+            min_pulse_us,
+            max_pulse_us,
+        }
+    }
+}
+
+impl Default for ServoCalibration {
+    /// the standard 1 ms to 2 ms range
+    fn default() -> Self {
+        Self::new(1000, 2000)
+    }
+}
+
+/**
+Servo driven by TC3 on pin 5
+*/
+pub struct Servo {
+    tc3: TC3,
+    _pin: Pin<Output, PE3>,
+    calibration: ServoCalibration,
+    angle: u8,
+}
+
+impl Servo {
+    pub fn new(tc3: TC3, pin: Pin<Output, PE3>, calibration: ServoCalibration) -> Self {
+        // fast PWM with ICR3 as TOP (mode 14), clear OC3A on compare match
+        tc3.tccr3a.write(|w| {
+            w.wgm3().bits(0b10);
+            w.com3a().match_clear()
+        });
+        tc3.icr3.write(|w| w.bits(FRAME_TOP));
+        // start the counter
+        tc3.tccr3b.write(|w| {
+            w.wgm3().bits(0b11);
+            w.cs3().prescale_8()
+        });
+
+        let mut servo = Self {
+            tc3,
+            _pin: pin,
+            calibration,
+            angle: 0,
+        };
+        servo.set_angle(0);
+        servo
+    }
+
+    /**
+    Sets a new calibration, it applies from the next angle on
+    */
+    pub fn set_calibration(&mut self, calibration: ServoCalibration) {
+        self.calibration = calibration;
+    }
+
+    /**
+    Sets the width of the pulse in microseconds
+
+    The width is clamped to the calibrated range so the servo never gets driven against its end stop
+    */
+    pub fn set_pulse_us(&mut self, pulse_us: u16) {
+        let pulse_us = pulse_us.clamp(self.calibration.min_pulse_us, self.calibration.max_pulse_us);
+        self.tc3.ocr3a.write(|w| w.bits(pulse_us * TICKS_PER_US));
+    }
+
+    /**
+    Sets the servo to an angle between 0 and MAX_ANGLE degrees
+    */
+    pub fn set_angle(&mut self, angle: u8) {
+        let angle = angle.min(MAX_ANGLE);
+        let range = (self.calibration.max_pulse_us - self.calibration.min_pulse_us) as u32;
+        let pulse_us =
+            self.calibration.min_pulse_us + (range * angle as u32 / MAX_ANGLE as u32) as u16;
+        self.set_pulse_us(pulse_us);
+        self.angle = angle;
+    }
+
+    /**
+    Moves the servo smoothly to the target angle within the given duration
+
+    The angle gets updated once every frame, so durations shorter than a frame result in a single
+    step to the target
+    */
+    pub async fn move_to_angle(&mut self, target: u8, duration_ms: u16, easing: Easing) {
+        let start = self.angle as i32;
+        let distance = target.min(MAX_ANGLE) as i32 - start;
+        let steps = (duration_ms / FRAME_MS).max(1) as u32;
+
+        for step in 1..=steps {
+            delay_ms(FRAME_MS).await;
+            let progress = easing.apply(step * PROGRESS_MAX / steps) as i32;
+            self.set_angle((start + distance * progress / PROGRESS_MAX as i32) as u8);
+        }
+    }
+}
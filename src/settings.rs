@@ -0,0 +1,332 @@
+//! This module holds the settings operators can change without reflashing the machine
+//!
+//! All tunables are gathered in a single record that is stored in the EEPROM. At boot the record is
+//! loaded, a record that does not load results in the defaults and a fault. The settings are the
+//! only copy of the tunables, every module reads the current settings whenever it needs them, so
+//! every update applies at once and is saved.
+//!
+//! The version has to be raised whenever the encoding of the record changes, records of an older
+//! version are discarded.
+//!
+//! How to use:
+//! Load the settings during setup after the store has been initialized, update them whenever the
+//! operator changes a setting
+
+use avr_device::interrupt;
+use core::cell::Cell;
+
+use crate::analog_joystick::AnalogCalibration;
+use crate::attract::AttractConfig;
+use crate::credit::CreditConfig;
+use crate::debounce;
+use crate::fault::{report, Fault};
+use crate::game::PAYOUT_MODE;
+use crate::grab::GrabConfig;
+use crate::grip::{GripProfile, PayoutMode, STRONG_GRIP, WEAK_GRIP};
+use crate::joystick::{GantryAxis, JoystickMapping, DIRECTIONS};
+use crate::log::info;
+use crate::play_timer;
+use crate::ramp::{RampCurve, RampShape};
+use crate::servo::ServoCalibration;
+use crate::stepper::StepperConfig;
+use crate::stepper::StepperDirection::{self, ClockWise, CounterClockWise, Idle};
+use crate::store::{self, RecordReader, RecordWriter};
+use crate::stuck_switch;
+use crate::Mutex;
+
+/// version of the encoding of the record
+const VERSION: u8 = 3;
+
+/// offset of the record in the EEPROM
+pub const OFFSET: u16 = 0;
+
+/// bytes of the encoded settings
+const SIZE: usize = 84;
+
+/// bytes the record takes in the EEPROM, further records follow behind
+pub const RECORD_SIZE: u16 = store::record_size(SIZE);
+
+/// the gantry axes by their encoding
+const AXES: [GantryAxis; 2] = [GantryAxis::X, GantryAxis::Y];
+
+/// the motor directions by their encoding
+const MOTOR_DIRECTIONS: [StepperDirection; 3] = [Idle, ClockWise, CounterClockWise];
+
+/**
+All settings of the machine
+*/
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub debounce_window_ms: u16,
+    pub stuck_threshold_ms: u16,
+    pub play_time_s: u8,
+    pub credit: CreditConfig,
+    pub attract: AttractConfig,
+    pub payout_mode: PayoutMode,
+    pub strong_grip: GripProfile,
+    pub weak_grip: GripProfile,
+    pub grab: GrabConfig,
+    pub ramp: RampCurve,
+    pub joystick: JoystickMapping,
+    pub stepper: StepperConfig,
+    pub servo: ServoCalibration,
+    pub analog: AnalogCalibration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            debounce_window_ms: debounce::DEFAULT_WINDOW_MS,
+            stuck_threshold_ms: stuck_switch::DEFAULT_THRESHOLD_MS,
+            play_time_s: play_timer::DEFAULT_PLAY_TIME_S,
+            credit: CreditConfig::default(),
+            attract: AttractConfig::default(),
+            payout_mode: PAYOUT_MODE,
+            strong_grip: STRONG_GRIP,
+            weak_grip: WEAK_GRIP,
+            grab: GrabConfig::default(),
+            ramp: RampCurve::default(),
+            joystick: JoystickMapping::default(),
+            stepper: StepperConfig::default(),
+            servo: ServoCalibration::default(),
+            analog: AnalogCalibration::default(),
+        }
+    }
+}
+
+impl Settings {
+    fn encode(&self) -> [u8; SIZE] {
+        let mut bytes = [0; SIZE];
+        let mut writer = RecordWriter::new(&mut bytes);
+
+        writer.u16(self.debounce_window_ms);
+        writer.u16(self.stuck_threshold_ms);
+        writer.u8(self.play_time_s);
+
+        writer.u8(self.credit.coins_per_play);
+        writer.u8(self.credit.bonus_every);
+        writer.bool(self.credit.free_play);
+
+        writer.u8(self.attract.idle_period_s);
+        writer.bool(self.attract.gantry_tours);
+
+        match self.payout_mode {
+            PayoutMode::Always => {
+                writer.u8(0);
+                writer.u16(0);
+            }
+            PayoutMode::EveryNth(n) => {
+                writer.u8(1);
+                writer.u16(n);
+            }
+            PayoutMode::Probability(percent) => {
+                writer.u8(2);
+                writer.u16(percent as u16);
+            }
+        }
+        for grip in [self.strong_grip, self.weak_grip] {
+            writer.u8(grip.closed_angle);
+            writer.u8(grip.hold_angle);
+        }
+
+        writer.i32(self.grab.drop_depth);
+        writer.u16(self.grab.drop_half_period_us);
+        writer.u16(self.grab.lift_half_period_us);
+        writer.u16(self.grab.return_half_period_us);
+        writer.u16(self.grab.close_delay_ms);
+        writer.u16(self.grab.claw_move_ms);
+        writer.u16(self.grab.bottom_hold_ms);
+        writer.u8(self.grab.regrip_count);
+        writer.u8(self.grab.regrip_release_deg);
+        writer.u16(self.grab.chute_hold_ms);
+        writer.u16(self.grab.prize_timeout_ms);
+
+        writer.u8(self.ramp.start_speed);
+        writer.u8(self.ramp.max_speed);
+        writer.u16(self.ramp.ramp_ms);
+        writer.u16(self.ramp.interval_ms);
+        writer.u8(match self.ramp.shape {
+            RampShape::Linear => 0,
+            RampShape::Quadratic => 1,
+        });
+
+        for direction in self.joystick.switches {
+            writer.u8(direction as u8);
+        }
+        for (axis, direction) in self.joystick.motors {
+            writer.u8(axis as u8);
+            writer.u8(direction as u8);
+        }
+
+        writer.i32(self.stepper.max_x_steps);
+        writer.i32(self.stepper.max_y_steps);
+        writer.i32(self.stepper.max_z_steps);
+        writer.u16(self.stepper.min_half_period_us);
+        writer.u16(self.stepper.max_half_period_us);
+
+        writer.u16(self.servo.min_pulse_us);
+        writer.u16(self.servo.max_pulse_us);
+
+        writer.u16(self.analog.min);
+        writer.u16(self.analog.max);
+        writer.u16(self.analog.dead_zone);
+
+        bytes
+    }
+
+    /**
+    Decodes the settings in the order they have been encoded
+
+    RETURNS: None if a bool or an enum holds an unknown value or a calibration has an invalid range
+    */
+    fn decode(bytes: &[u8; SIZE]) -> Option<Self> {
+        let mut reader = RecordReader::new(bytes);
+
+        let debounce_window_ms = reader.u16();
+        let stuck_threshold_ms = reader.u16();
+        let play_time_s = reader.u8();
+
+        let credit = CreditConfig {
+            coins_per_play: reader.u8(),
+            bonus_every: reader.u8(),
+            free_play: reader.bool()?,
+        };
+
+        let attract = AttractConfig {
+            idle_period_s: reader.u8(),
+            gantry_tours: reader.bool()?,
+        };
+
+        let payout_mode = match (reader.u8(), reader.u16()) {
+            (0, _) => PayoutMode::Always,
+            (1, n) => PayoutMode::EveryNth(n),
+            (2, percent) if percent <= 100 => PayoutMode::Probability(percent as u8),
+            _ => return None,
+        };
+        let strong_grip = GripProfile {
+            closed_angle: reader.u8(),
+            hold_angle: reader.u8(),
+        };
+        let weak_grip = GripProfile {
+            closed_angle: reader.u8(),
+            hold_angle: reader.u8(),
+        };
+
+        let grab = GrabConfig {
+            drop_depth: reader.i32(),
+            drop_half_period_us: reader.u16(),
+            lift_half_period_us: reader.u16(),
+            return_half_period_us: reader.u16(),
+            close_delay_ms: reader.u16(),
+            claw_move_ms: reader.u16(),
+            bottom_hold_ms: reader.u16(),
+            regrip_count: reader.u8(),
+            regrip_release_deg: reader.u8(),
+            chute_hold_ms: reader.u16(),
+            prize_timeout_ms: reader.u16(),
+        };
+
+        let ramp = RampCurve {
+            start_speed: reader.u8(),
+            max_speed: reader.u8(),
+            ramp_ms: reader.u16(),
+            interval_ms: reader.u16(),
+            shape: match reader.u8() {
+                0 => RampShape::Linear,
+                1 => RampShape::Quadratic,
+                _ => return None,
+            },
+        };
+
+        let mut joystick = JoystickMapping::default();
+        for direction in joystick.switches.iter_mut() {
+            *direction = *DIRECTIONS.get(reader.u8() as usize)?;
+        }
+        for (axis, direction) in joystick.motors.iter_mut() {
+            *axis = *AXES.get(reader.u8() as usize)?;
+            *direction = *MOTOR_DIRECTIONS.get(reader.u8() as usize)?;
+        }
+
+        let stepper = StepperConfig {
+            max_x_steps: reader.i32(),
+            max_y_steps: reader.i32(),
+            max_z_steps: reader.i32(),
+            min_half_period_us: reader.u16(),
+            max_half_period_us: reader.u16(),
+        };
+
+        let servo = ServoCalibration::new(reader.u16(), reader.u16());
+        let analog = AnalogCalibration {
+            min: reader.u16(),
+            max: reader.u16(),
+            dead_zone: reader.u16(),
+        };
+        // the same ranges as the shell enforces, the axes, the servo and the stick can not work with
+        // an empty or inverted range
+        let valid = stepper.max_x_steps > 0
+            && stepper.max_y_steps > 0
+            && stepper.max_z_steps > 0
+            && stepper.min_half_period_us <= stepper.max_half_period_us
+            && (0..=stepper.max_z_steps).contains(&grab.drop_depth)
+            && servo.min_pulse_us < servo.max_pulse_us
+            && analog.min <= analog.max;
+        if !valid {
+            return None;
+        }
+
+        Some(Self {
+            debounce_window_ms,
+            stuck_threshold_ms,
+            play_time_s,
+            credit,
+            attract,
+            payout_mode,
+            strong_grip,
+            weak_grip,
+            grab,
+            ramp,
+            joystick,
+            stepper,
+            servo,
+            analog,
+        })
+    }
+}
+
+/// The current settings, None until the settings have been loaded
+static SETTINGS: Mutex<Cell<Option<Settings>>> = Mutex::new(Cell::new(None));
+
+/**
+Gets the current settings, the defaults until the settings have been loaded
+*/
+pub fn current() -> Settings {
+    interrupt::free(|cs| SETTINGS.borrow(cs).get()).unwrap_or_default()
+}
+
+/**
+Loads the settings from the EEPROM, a record that does not load is reported and
+replaced by the defaults
+*/
+pub fn load() {
+    let mut bytes = [0; SIZE];
+    let settings = if store::load(OFFSET, VERSION, &mut bytes) {
+        Settings::decode(&bytes)
+    } else {
+        None
+    };
+
+    let settings = settings.unwrap_or_else(|| {
+        report(Fault::InvalidSettings);
+        Settings::default()
+    });
+    interrupt::free(|cs| SETTINGS.borrow(cs).set(Some(settings)));
+}
+
+/**
+Applies new settings and saves them, only the bytes that changed are written to the EEPROM
+*/
+pub fn update(settings: Settings) {
+    interrupt::free(|cs| SETTINGS.borrow(cs).set(Some(settings)));
+    store::save(OFFSET, VERSION, &settings.encode());
+    info!("settings saved");
+}
@@ -0,0 +1,732 @@
+//! This module holds the service menu operators reach over the serial console
+//!
+//! The shell reads lines on USART0 and answers every command on the console
+//!
+//! help => lists all commands
+//! status => prints the game state, the credits, the play time left and the last fault
+//! home => homes all axes
+//! jog <x|y|z> <steps> => moves an axis by the given steps, negative steps move towards home
+//! claw <open|close|angle> => moves the claw
+//! get [name] => prints a single setting or all settings
+//! set <name> <value> => changes a setting and saves the settings
+//! map [switches <r|l|f|b> x4 | invert <x|y> | swap | default] => prints or changes the joystick
+//! mapping, the switches are given in the order of the pins 50 to 53
+//! stats => prints the statistics
+//! reset-stats => resets the resettable counters
+//!
+//! The shell runs alongside the tasks of every game state. Commands that move the machine or write
+//! to the EEPROM are only accepted while the machine is idle and an operator at the cabinet has
+//! unlocked them with a long press of the start button. The shell hands them to the service task of
+//! the idle state through a channel. Every change of the game state locks them again.
+//!
+//! How to use:
+//! Run the shell task in every state, in the idle state together with the service task, and unlock
+//! the service commands on a long press of the start button
+
+use avr_device::interrupt;
+use core::cell::{Cell, RefCell};
+use core::str::SplitAsciiWhitespace;
+use heapless::Vec;
+
+use crate::channel::{Receiver, Sender};
+use crate::credit;
+use crate::fault::{self, report};
+use crate::game::{self, CLAW_OPEN_ANGLE};
+use crate::grab::home;
+use crate::grip::PayoutMode;
+use crate::joystick::{GantryAxis, JoystickDirection, JoystickMapping, DIRECTIONS};
+use crate::log::info;
+use crate::play_timer;
+use crate::serial;
+use crate::servo::Servo;
+use crate::settings::{self, Settings};
+use crate::stats;
+use crate::stepper::Axis;
+use crate::stepper::StepperDirection::{ClockWise, CounterClockWise, Idle};
+use crate::Mutex;
+
+/// the longest line the shell accepts, further characters are dropped
+const MAX_LINE: usize = 32;
+
+/// half of the period of one step while jogging an axis
+const JOG_HALF_PERIOD_US: u16 = 2000;
+
+/// the line typed so far, it survives a change of the game state
+static LINE: Mutex<RefCell<Vec<u8, MAX_LINE>>> = Mutex::new(RefCell::new(Vec::new()));
+
+/// true once the service commands have been unlocked in the current state
+static UNLOCKED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/**
+All axes that can be jogged
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum JogAxis {
+    X,
+    Y,
+    Z,
+}
+
+/**
+Commands the shell hands to the service task
+home => homes all axes
+jog => moves an axis by the given steps
+claw => moves the claw to the given angle
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ServiceCommand {
+    Home,
+    Jog(JogAxis, i32),
+    Claw(u8),
+}
+
+/**
+A setting that can be read and changed in the shell, set returns false if the value is out of range
+*/
+struct Setting {
+    name: &'static str,
+    get: fn(&Settings) -> i32,
+    set: fn(&mut Settings, i32) -> bool,
+}
+
+fn to_bool(value: i32) -> Option<bool> {
+    match value {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+/// all settings of the shell
+const SETTINGS: [Setting; 38] = [
+    Setting {
+        name: "debounce-ms",
+        get: |s| s.debounce_window_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.debounce_window_ms = v).is_ok(),
+    },
+    Setting {
+        name: "stuck-ms",
+        get: |s| s.stuck_threshold_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.stuck_threshold_ms = v).is_ok(),
+    },
+    Setting {
+        name: "play-time-s",
+        get: |s| s.play_time_s as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.play_time_s = v).is_ok(),
+    },
+    Setting {
+        name: "coins-per-play",
+        get: |s| s.credit.coins_per_play as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.credit.coins_per_play = v).is_ok(),
+    },
+    Setting {
+        name: "bonus-every",
+        get: |s| s.credit.bonus_every as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.credit.bonus_every = v).is_ok(),
+    },
+    Setting {
+        name: "free-play",
+        get: |s| s.credit.free_play as i32,
+        set: |s, v| to_bool(v).map(|v| s.credit.free_play = v).is_some(),
+    },
+    Setting {
+        name: "idle-s",
+        get: |s| s.attract.idle_period_s as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.attract.idle_period_s = v).is_ok(),
+    },
+    Setting {
+        name: "tours",
+        get: |s| s.attract.gantry_tours as i32,
+        set: |s, v| to_bool(v).map(|v| s.attract.gantry_tours = v).is_some(),
+    },
+    // 1 grips strong in every game, 0 while another payout mode is set
+    Setting {
+        name: "payout-nth",
+        get: |s| match s.payout_mode {
+            PayoutMode::Always => 1,
+            PayoutMode::EveryNth(n) => n as i32,
+            PayoutMode::Probability(_) => 0,
+        },
+        set: |s, v| {
+            s.payout_mode = match u16::try_from(v) {
+                Ok(1) => PayoutMode::Always,
+                Ok(n) if n > 1 => PayoutMode::EveryNth(n),
+                _ => return false,
+            };
+            true
+        },
+    },
+    // 0 while another payout mode is set
+    Setting {
+        name: "payout-percent",
+        get: |s| match s.payout_mode {
+            PayoutMode::Probability(percent) => percent as i32,
+            _ => 0,
+        },
+        set: |s, v| {
+            s.payout_mode = match u8::try_from(v) {
+                Ok(percent) if percent <= 100 => PayoutMode::Probability(percent),
+                _ => return false,
+            };
+            true
+        },
+    },
+    Setting {
+        name: "strong-closed",
+        get: |s| s.strong_grip.closed_angle as i32,
+        set: |s, v| {
+            u8::try_from(v)
+                .map(|v| s.strong_grip.closed_angle = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "strong-hold",
+        get: |s| s.strong_grip.hold_angle as i32,
+        set: |s, v| {
+            u8::try_from(v)
+                .map(|v| s.strong_grip.hold_angle = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "weak-closed",
+        get: |s| s.weak_grip.closed_angle as i32,
+        set: |s, v| {
+            u8::try_from(v)
+                .map(|v| s.weak_grip.closed_angle = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "weak-hold",
+        get: |s| s.weak_grip.hold_angle as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.weak_grip.hold_angle = v).is_ok(),
+    },
+    Setting {
+        name: "drop-depth",
+        get: |s| s.grab.drop_depth,
+        set: |s, v| {
+            let valid = (0..=s.stepper.max_z_steps).contains(&v);
+            if valid {
+                s.grab.drop_depth = v;
+            }
+            valid
+        },
+    },
+    Setting {
+        name: "drop-half-us",
+        get: |s| s.grab.drop_half_period_us as i32,
+        set: |s, v| {
+            u16::try_from(v)
+                .map(|v| s.grab.drop_half_period_us = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "lift-half-us",
+        get: |s| s.grab.lift_half_period_us as i32,
+        set: |s, v| {
+            u16::try_from(v)
+                .map(|v| s.grab.lift_half_period_us = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "return-half-us",
+        get: |s| s.grab.return_half_period_us as i32,
+        set: |s, v| {
+            u16::try_from(v)
+                .map(|v| s.grab.return_half_period_us = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "close-delay-ms",
+        get: |s| s.grab.close_delay_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.grab.close_delay_ms = v).is_ok(),
+    },
+    Setting {
+        name: "claw-move-ms",
+        get: |s| s.grab.claw_move_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.grab.claw_move_ms = v).is_ok(),
+    },
+    Setting {
+        name: "bottom-hold-ms",
+        get: |s| s.grab.bottom_hold_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.grab.bottom_hold_ms = v).is_ok(),
+    },
+    Setting {
+        name: "regrip-count",
+        get: |s| s.grab.regrip_count as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.grab.regrip_count = v).is_ok(),
+    },
+    Setting {
+        name: "regrip-deg",
+        get: |s| s.grab.regrip_release_deg as i32,
+        set: |s, v| {
+            u8::try_from(v)
+                .map(|v| s.grab.regrip_release_deg = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "chute-hold-ms",
+        get: |s| s.grab.chute_hold_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.grab.chute_hold_ms = v).is_ok(),
+    },
+    Setting {
+        name: "prize-timeout-ms",
+        get: |s| s.grab.prize_timeout_ms as i32,
+        set: |s, v| {
+            u16::try_from(v)
+                .map(|v| s.grab.prize_timeout_ms = v)
+                .is_ok()
+        },
+    },
+    Setting {
+        name: "ramp-start",
+        get: |s| s.ramp.start_speed as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.ramp.start_speed = v).is_ok(),
+    },
+    Setting {
+        name: "ramp-max",
+        get: |s| s.ramp.max_speed as i32,
+        set: |s, v| u8::try_from(v).map(|v| s.ramp.max_speed = v).is_ok(),
+    },
+    Setting {
+        name: "ramp-ms",
+        get: |s| s.ramp.ramp_ms as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.ramp.ramp_ms = v).is_ok(),
+    },
+    Setting {
+        name: "max-x",
+        get: |s| s.stepper.max_x_steps,
+        set: |s, v| {
+            let valid = v > 0;
+            if valid {
+                s.stepper.max_x_steps = v;
+            }
+            valid
+        },
+    },
+    Setting {
+        name: "max-y",
+        get: |s| s.stepper.max_y_steps,
+        set: |s, v| {
+            let valid = v > 0;
+            if valid {
+                s.stepper.max_y_steps = v;
+            }
+            valid
+        },
+    },
+    Setting {
+        name: "max-z",
+        get: |s| s.stepper.max_z_steps,
+        set: |s, v| {
+            // the claw has to be able to reach the drop depth
+            let valid = v > 0 && v >= s.grab.drop_depth;
+            if valid {
+                s.stepper.max_z_steps = v;
+            }
+            valid
+        },
+    },
+    // the half periods of the gantry at full and at the slowest speed
+    Setting {
+        name: "gantry-fast-us",
+        get: |s| s.stepper.min_half_period_us as i32,
+        set: |s, v| match u16::try_from(v) {
+            Ok(v) if v <= s.stepper.max_half_period_us => {
+                s.stepper.min_half_period_us = v;
+                true
+            }
+            _ => false,
+        },
+    },
+    Setting {
+        name: "gantry-slow-us",
+        get: |s| s.stepper.max_half_period_us as i32,
+        set: |s, v| match u16::try_from(v) {
+            Ok(v) if v >= s.stepper.min_half_period_us => {
+                s.stepper.max_half_period_us = v;
+                true
+            }
+            _ => false,
+        },
+    },
+    Setting {
+        name: "servo-min-us",
+        get: |s| s.servo.min_pulse_us as i32,
+        set: |s, v| match u16::try_from(v) {
+            Ok(v) if v < s.servo.max_pulse_us => {
+                s.servo.min_pulse_us = v;
+                true
+            }
+            _ => false,
+        },
+    },
+    Setting {
+        name: "servo-max-us",
+        get: |s| s.servo.max_pulse_us as i32,
+        set: |s, v| match u16::try_from(v) {
+            Ok(v) if v > s.servo.min_pulse_us => {
+                s.servo.max_pulse_us = v;
+                true
+            }
+            _ => false,
+        },
+    },
+    Setting {
+        name: "stick-min",
+        get: |s| s.analog.min as i32,
+        set: |s, v| match u16::try_from(v) {
+            Ok(v) if v <= s.analog.max => {
+                s.analog.min = v;
+                true
+            }
+            _ => false,
+        },
+    },
+    Setting {
+        name: "stick-max",
+        get: |s| s.analog.max as i32,
+        set: |s, v| match u16::try_from(v) {
+            Ok(v) if v >= s.analog.min => {
+                s.analog.max = v;
+                true
+            }
+            _ => false,
+        },
+    },
+    Setting {
+        name: "stick-dead-zone",
+        get: |s| s.analog.dead_zone as i32,
+        set: |s, v| u16::try_from(v).map(|v| s.analog.dead_zone = v).is_ok(),
+    },
+];
+
+async fn print_line(line: &str) {
+    serial::write(line.as_bytes()).await;
+    serial::write(b"\r\n").await;
+}
+
+/**
+Waits for the next line that is not empty, every character is echoed and backspace removes the last
+character
+*/
+async fn read_line() -> Vec<u8, MAX_LINE> {
+    loop {
+        let byte = serial::read().await;
+        match byte {
+            b'\r' | b'\n' => {
+                let line =
+                    interrupt::free(|cs| core::mem::take(&mut *LINE.borrow(cs).borrow_mut()));
+                serial::write(b"\r\n").await;
+                if !line.is_empty() {
+                    return line;
+                }
+            }
+            0x08 | 0x7F => {
+                if interrupt::free(|cs| LINE.borrow(cs).borrow_mut().pop().is_some()) {
+                    serial::write(b"\x08 \x08").await;
+                }
+            }
+            _ => {
+                if interrupt::free(|cs| LINE.borrow(cs).borrow_mut().push(byte).is_ok()) {
+                    serial::write(&[byte]).await;
+                }
+            }
+        }
+    }
+}
+
+async fn print_help() {
+    print_line("commands: help, status, home, jog <x|y|z> <steps>, claw <open|close|angle>,").await;
+    print_line("get [name], set <name> <value>, map [switches|invert|swap|default], stats,").await;
+    print_line("reset-stats").await;
+    print_line("changing the machine needs a long press of start while idle").await;
+}
+
+async fn print_status() {
+    let state = game::current_state();
+    let credits = credit::credits();
+    let free_play = settings::current().credit.free_play;
+    let last_fault = fault::last().map_or("none", |fault| fault.name());
+    serial::print(|line| ufmt::uwrite!(line, "state: {}", state.name())).await;
+    if free_play {
+        serial::print(|line| ufmt::uwrite!(line, "credits: {} (free play)", credits)).await;
+    } else {
+        serial::print(|line| ufmt::uwrite!(line, "credits: {}", credits)).await;
+    }
+    if let Some(remaining_s) = play_timer::remaining_s() {
+        serial::print(|line| ufmt::uwrite!(line, "time left: {} s", remaining_s)).await;
+    }
+    serial::print(|line| ufmt::uwrite!(line, "last fault: {}", last_fault)).await;
+}
+
+async fn print_setting(setting: &Setting, settings: &Settings) {
+    serial::print(|line| ufmt::uwrite!(line, "{}: {}", setting.name, (setting.get)(settings)))
+        .await;
+}
+
+fn direction_name(direction: JoystickDirection) -> &'static str {
+    match direction {
+        JoystickDirection::RIGHT => "r",
+        JoystickDirection::LEFT => "l",
+        JoystickDirection::FORWARD => "f",
+        JoystickDirection::BACKWARD => "b",
+    }
+}
+
+/**
+Prints the logical direction of every switch and the motor every direction drives, + moves away
+from home
+*/
+async fn print_mapping() {
+    let mapping = settings::current().joystick;
+    let [pin_50, pin_51, pin_52, pin_53] = mapping.switches.map(direction_name);
+    serial::print(|line| {
+        ufmt::uwrite!(
+            line,
+            "switches: {} {} {} {}",
+            pin_50,
+            pin_51,
+            pin_52,
+            pin_53
+        )
+    })
+    .await;
+    for direction in DIRECTIONS {
+        let (axis, motor_direction) = mapping.motors[direction as usize];
+        let axis = match axis {
+            GantryAxis::X => "x",
+            GantryAxis::Y => "y",
+        };
+        let motor_direction = match motor_direction {
+            ClockWise => "+",
+            CounterClockWise => "-",
+            Idle => "0",
+        };
+        let name = direction_name(direction);
+        serial::print(|line| ufmt::uwrite!(line, "{}: {}{}", name, axis, motor_direction)).await;
+    }
+}
+
+/**
+Changes the given mapping as the words of a map command say
+
+RETURNS: None if the words are not a valid change
+*/
+fn parse_mapping(
+    change: &str,
+    words: &mut SplitAsciiWhitespace,
+    mut mapping: JoystickMapping,
+) -> Option<JoystickMapping> {
+    match change {
+        "switches" => {
+            for switch in mapping.switches.iter_mut() {
+                *switch = match words.next()? {
+                    "r" => JoystickDirection::RIGHT,
+                    "l" => JoystickDirection::LEFT,
+                    "f" => JoystickDirection::FORWARD,
+                    "b" => JoystickDirection::BACKWARD,
+                    _ => return None,
+                };
+            }
+        }
+        "invert" => mapping.invert(match words.next()? {
+            "x" => GantryAxis::X,
+            "y" => GantryAxis::Y,
+            _ => return None,
+        }),
+        "swap" => mapping.swap_axes(),
+        "default" => mapping = JoystickMapping::default(),
+        _ => return None,
+    }
+    Some(mapping)
+}
+
+fn find_setting(name: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.name == name)
+}
+
+fn parse_jog(axis: Option<&str>, steps: Option<&str>) -> Option<ServiceCommand> {
+    let axis = match axis? {
+        "x" => JogAxis::X,
+        "y" => JogAxis::Y,
+        "z" => JogAxis::Z,
+        _ => return None,
+    };
+    Some(ServiceCommand::Jog(axis, steps?.parse().ok()?))
+}
+
+fn parse_claw(angle: Option<&str>) -> Option<ServiceCommand> {
+    let angle = match angle? {
+        "open" => CLAW_OPEN_ANGLE,
+        "close" => settings::current().strong_grip.closed_angle,
+        angle => angle.parse().ok()?,
+    };
+    Some(ServiceCommand::Claw(angle))
+}
+
+/**
+Unlocks the service commands until the game state changes
+*/
+pub fn unlock() {
+    interrupt::free(|cs| UNLOCKED.borrow(cs).set(true));
+    info!("service unlocked");
+}
+
+/**
+Gets the sender of the service task if service commands are accepted right now
+
+RETURNS: the error to print while the machine is busy or the service commands are locked
+*/
+fn service_sender<'a, 'b>(
+    service: &'a Option<Sender<'b, ServiceCommand>>,
+) -> Result<&'a Sender<'b, ServiceCommand>, &'static str> {
+    match service {
+        None => Err("error: busy, only while idle"),
+        Some(_) if !interrupt::free(|cs| UNLOCKED.borrow(cs).get()) => {
+            Err("error: locked, hold the start button")
+        }
+        Some(sender) => Ok(sender),
+    }
+}
+
+/**
+Runs a single line, commands that need the idle state are refused without a service sender
+*/
+async fn execute(line: &str, service: &Option<Sender<'_, ServiceCommand>>) {
+    let mut words = line.split_ascii_whitespace();
+    let command = words.next().unwrap_or("");
+
+    let service_command = match command {
+        "help" => return print_help().await,
+        "status" => return print_status().await,
+        "stats" => return stats::print().await,
+        "get" => {
+            let settings = settings::current();
+            match words.next() {
+                None => {
+                    for setting in SETTINGS.iter() {
+                        print_setting(setting, &settings).await;
+                    }
+                }
+                Some(name) => match find_setting(name) {
+                    Some(setting) => print_setting(setting, &settings).await,
+                    None => print_line("error: unknown setting").await,
+                },
+            }
+            return;
+        }
+        "set" => {
+            if let Err(error) = service_sender(service) {
+                return print_line(error).await;
+            }
+            let setting = words.next().and_then(find_setting);
+            let value = words.next().and_then(|value| value.parse().ok());
+            let (Some(setting), Some(value)) = (setting, value) else {
+                return print_line("error: usage set <name> <value>").await;
+            };
+            let mut settings = settings::current();
+            if (setting.set)(&mut settings, value) {
+                settings::update(settings);
+                print_line("ok").await;
+            } else {
+                print_line("error: value out of range").await;
+            }
+            return;
+        }
+        "map" => {
+            let Some(change) = words.next() else {
+                return print_mapping().await;
+            };
+            if let Err(error) = service_sender(service) {
+                return print_line(error).await;
+            }
+            let mut settings = settings::current();
+            match parse_mapping(change, &mut words, settings.joystick) {
+                Some(mapping) => {
+                    settings.joystick = mapping;
+                    settings::update(settings);
+                    print_line("ok").await;
+                }
+                None => print_line("error: usage map [switches|invert|swap|default]").await,
+            }
+            return;
+        }
+        "reset-stats" => {
+            if let Err(error) = service_sender(service) {
+                return print_line(error).await;
+            }
+            stats::reset();
+            return print_line("ok").await;
+        }
+        "home" => Some(ServiceCommand::Home),
+        "jog" => parse_jog(words.next(), words.next()),
+        "claw" => parse_claw(words.next()),
+        _ => return print_line("error: unknown command, try help").await,
+    };
+
+    let Some(service_command) = service_command else {
+        return print_line("error: invalid arguments").await;
+    };
+    match service_sender(service) {
+        Ok(sender) => sender.send(service_command),
+        Err(error) => print_line(error).await,
+    }
+}
+
+/**
+Task that reads commands from the console and runs them
+
+The service sender is only given while idle, without it the machine is busy. Every state starts a
+new shell task, which locks the service commands again.
+*/
+pub async fn shell_task(service: Option<Sender<'_, ServiceCommand>>) {
+    interrupt::free(|cs| UNLOCKED.borrow(cs).set(false));
+    loop {
+        let line = read_line().await;
+        match core::str::from_utf8(&line) {
+            Ok(line) => execute(line, &service).await,
+            Err(_) => print_line("error: invalid characters").await,
+        }
+    }
+}
+
+/**
+Task that runs the commands of the shell that move the machine
+*/
+pub async fn service_task(
+    mut receiver: Receiver<'_, ServiceCommand>,
+    x_axis: &mut Axis,
+    y_axis: &mut Axis,
+    z_axis: &mut Axis,
+    claw: &mut Servo,
+) {
+    loop {
+        match receiver.receive().await {
+            ServiceCommand::Home => {
+                if let Err(fault) = home(x_axis, y_axis, z_axis).await {
+                    report(fault);
+                    continue;
+                }
+            }
+            ServiceCommand::Jog(axis, steps) => {
+                let axis = match axis {
+                    JogAxis::X => &mut *x_axis,
+                    JogAxis::Y => &mut *y_axis,
+                    JogAxis::Z => &mut *z_axis,
+                };
+                // the target is clamped to the range of the axis
+                let target = axis.position().saturating_add(steps);
+                axis.move_to(target, JOG_HALF_PERIOD_US).await;
+            }
+            ServiceCommand::Claw(angle) => claw.set_angle(angle),
+        }
+        print_line("ok").await;
+    }
+}
@@ -0,0 +1,199 @@
+//! This module holds the states of the game and the transitions between them
+//!
+//! Every state ends with an event, the transition table decides which state follows. An event that
+//! is not allowed in a state has no transition, the game treats it as an unknown state.
+//!
+//! How to use:
+//! Run the state, then look up the next state with the event the state ended with
+
+/**
+All possible game states
+resetting => machine homes all axes before a new round
+idle => machine is ready for a new round and waits for the start button
+attract => nobody played for a while, the machine tries to attract players
+running => one is currently playing the game
+finished => one has finished the game and the claw grabs
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameState {
+    RESETTING,
+    IDLE,
+    ATTRACT,
+    RUNNING,
+    FINISHED,
+}
+
+/**
+All events that advance the game
+homed => all axes reached their limit switches
+start pressed => the player started a game
+idle timeout => nobody played for the idle period
+player arrived => a coin or the start button ended the attract mode
+end pressed => the player ended the game
+time up => the play time is over
+grabbed => the grab sequence is over
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameEvent {
+    Homed,
+    StartPressed,
+    IdleTimeout,
+    PlayerArrived,
+    EndPressed,
+    TimeUp,
+    Grabbed,
+}
+
+/// all allowed transitions, the state, the event in that state and the next state
+pub const TRANSITIONS: [(GameState, GameEvent, GameState); 7] = [
+    (GameState::RESETTING, GameEvent::Homed, GameState::IDLE),
+    (GameState::IDLE, GameEvent::StartPressed, GameState::RUNNING),
+    (GameState::IDLE, GameEvent::IdleTimeout, GameState::ATTRACT),
+    // a tour may have left the gantry anywhere, so it has to be homed again
+    (
+        GameState::ATTRACT,
+        GameEvent::PlayerArrived,
+        GameState::RESETTING,
+    ),
+    (
+        GameState::RUNNING,
+        GameEvent::EndPressed,
+        GameState::FINISHED,
+    ),
+    (GameState::RUNNING, GameEvent::TimeUp, GameState::FINISHED),
+    (
+        GameState::FINISHED,
+        GameEvent::Grabbed,
+        GameState::RESETTING,
+    ),
+];
+
+impl GameState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameState::RESETTING => "resetting",
+            GameState::IDLE => "idle",
+            GameState::ATTRACT => "attract",
+            GameState::RUNNING => "running",
+            GameState::FINISHED => "finished",
+        }
+    }
+
+    /**
+    Looks up the next state in the transition table
+
+    RETURNS: None if the event is not allowed in this state
+    */
+    pub fn next(&self, event: GameEvent) -> Option<GameState> {
+        TRANSITIONS
+            .iter()
+            .find(|(state, transition_event, _)| state == self && *transition_event == event)
+            .map(|(_, _, next)| *next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [GameState; 5] = [
+        GameState::RESETTING,
+        GameState::IDLE,
+        GameState::ATTRACT,
+        GameState::RUNNING,
+        GameState::FINISHED,
+    ];
+
+    const EVENTS: [GameEvent; 7] = [
+        GameEvent::Homed,
+        GameEvent::StartPressed,
+        GameEvent::IdleTimeout,
+        GameEvent::PlayerArrived,
+        GameEvent::EndPressed,
+        GameEvent::TimeUp,
+        GameEvent::Grabbed,
+    ];
+
+    #[test]
+    fn allowed_transitions() {
+        use GameEvent::*;
+        use GameState::*;
+
+        assert_eq!(RESETTING.next(Homed), Some(IDLE));
+        assert_eq!(IDLE.next(StartPressed), Some(RUNNING));
+        assert_eq!(IDLE.next(IdleTimeout), Some(ATTRACT));
+        assert_eq!(ATTRACT.next(PlayerArrived), Some(RESETTING));
+        assert_eq!(RUNNING.next(EndPressed), Some(FINISHED));
+        assert_eq!(RUNNING.next(TimeUp), Some(FINISHED));
+        assert_eq!(FINISHED.next(Grabbed), Some(RESETTING));
+    }
+
+    #[test]
+    fn other_events_are_not_allowed() {
+        for state in STATES {
+            for event in EVENTS {
+                let listed = TRANSITIONS
+                    .iter()
+                    .any(|(s, e, _)| *s == state && *e == event);
+                assert_eq!(state.next(event).is_some(), listed);
+            }
+        }
+        assert_eq!(GameState::RUNNING.next(GameEvent::Homed), None);
+        assert_eq!(GameState::IDLE.next(GameEvent::Grabbed), None);
+    }
+
+    #[test]
+    fn transitions_are_unambiguous() {
+        for (index, (state, event, _)) in TRANSITIONS.iter().enumerate() {
+            assert!(!TRANSITIONS[index + 1..]
+                .iter()
+                .any(|(s, e, _)| s == state && e == event));
+        }
+    }
+
+    #[test]
+    fn every_state_is_reached_from_resetting() {
+        let mut reached = [GameState::RESETTING; 5];
+        let mut count = 1;
+        let mut index = 0;
+        while index < count {
+            for event in EVENTS {
+                if let Some(next) = reached[index].next(event) {
+                    if !reached[..count].contains(&next) {
+                        reached[count] = next;
+                        count += 1;
+                    }
+                }
+            }
+            index += 1;
+        }
+        assert_eq!(count, STATES.len());
+    }
+
+    #[test]
+    fn every_state_leads_back_to_resetting() {
+        for state in STATES {
+            let mut current = state;
+            // the longest way back passes every other state once
+            for _ in 0..STATES.len() {
+                if current == GameState::RESETTING {
+                    break;
+                }
+                current = EVENTS
+                    .iter()
+                    .find_map(|event| current.next(*event))
+                    .expect("state without a transition");
+            }
+            assert_eq!(current, GameState::RESETTING);
+        }
+    }
+
+    #[test]
+    fn names_are_unique() {
+        for (index, state) in STATES.iter().enumerate() {
+            assert!(!STATES[index + 1..]
+                .iter()
+                .any(|other| other.name() == state.name()));
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! This module keeps the audit counters of the claw machine
+//!
+//! The game counts plays, strong grips, prizes won, the play time and the coins inserted, every
+//! reported fault is counted by its kind. Comparing the prizes won with the plays tells operators
+//! the actual payout. All counters exist twice, the resettable counters start over once the
+//! operator resets them while the lifetime counters keep counting for the whole life of the machine.
+//!
+//! The counters are saved to the EEPROM at a fixed offset behind the room reserved for the settings,
+//! so a growing settings record does not move them. To spare the EEPROM they are saved at most once
+//! per save interval and only while nobody plays, a power loss thus loses at most the counts of that
+//! interval.
+//!
+//! How to use:
+//! Load the counters during setup after the store has been initialized, record the events of the
+//! game and run the save task whenever nobody plays
+
+use avr_device::interrupt;
+use core::cell::{Cell, RefCell};
+
+use crate::fault::{self, report, Fault};
+use crate::log::debug;
+use crate::serial;
+use crate::settings;
+use crate::store::{self, RecordReader, RecordWriter};
+use crate::timer::{delay_s, PrecisionTicker};
+use crate::Mutex;
+
+/// version of the encoding of the record
+const VERSION: u8 = 2;
+
+/// offset of the record in the EEPROM, the settings may grow up to this offset
+const OFFSET: u16 = 256;
+
+// the settings record must never run into the counters
+const _: () = assert!(settings::OFFSET + settings::RECORD_SIZE <= OFFSET);
+
+/// bytes of the encoded counters of one set
+const COUNTERS_SIZE: usize = 4 + 4 + 4 + 4 + 8 + 2 * fault::KINDS;
+
+/// bytes of the encoded statistics
+const SIZE: usize = 2 * COUNTERS_SIZE;
+
+/// minimum time between two saves
+const SAVE_INTERVAL_MS: u64 = 15 * 60 * 1000;
+
+/// time between two checks whether the counters are due to be saved
+const SAVE_CHECK_S: u8 = 60;
+
+/**
+A set of counters
+plays => games that have been started
+strong grips => games the payout mode decided to grip strong
+wins => prizes that passed the prize chute sensor
+coins => coins inserted
+play time ms => total time of all games, from the start of the game until the claw drops
+faults => reported faults, indexed by Fault::index
+*/
+#[derive(Clone, Copy)]
+pub struct Counters {
+    pub plays: u32,
+    pub strong_grips: u32,
+    pub wins: u32,
+    pub coins: u32,
+    pub play_time_ms: u64,
+    pub faults: [u16; fault::KINDS],
+}
+
+impl Counters {
+    pub const ZERO: Counters = Counters {
+        plays: 0,
+        strong_grips: 0,
+        wins: 0,
+        coins: 0,
+        play_time_ms: 0,
+        faults: [0; fault::KINDS],
+    };
+
+    /**
+    Gets the average time of a game in milliseconds, 0 without any game
+    */
+    pub fn average_play_ms(&self) -> u64 {
+        if self.plays == 0 {
+            0
+        } else {
+            self.play_time_ms / self.plays as u64
+        }
+    }
+
+    /**
+    Gets the prizes won in per mille of all plays, 0 without any game
+    */
+    pub fn payout_permille(&self) -> u32 {
+        if self.plays == 0 {
+            0
+        } else {
+            (self.wins as u64 * 1000 / self.plays as u64) as u32
+        }
+    }
+
+    fn encode(&self, writer: &mut RecordWriter) {
+        writer.u32(self.plays);
+        writer.u32(self.strong_grips);
+        writer.u32(self.wins);
+        writer.u32(self.coins);
+        writer.u64(self.play_time_ms);
+        for count in self.faults {
+            writer.u16(count);
+        }
+    }
+
+    fn decode(reader: &mut RecordReader) -> Self {
+        let mut counters = Counters {
+            plays: reader.u32(),
+            strong_grips: reader.u32(),
+            wins: reader.u32(),
+            coins: reader.u32(),
+            play_time_ms: reader.u64(),
+            faults: [0; fault::KINDS],
+        };
+        for count in counters.faults.iter_mut() {
+            *count = reader.u16();
+        }
+        counters
+    }
+}
+
+/**
+The resettable and the lifetime counters
+*/
+#[derive(Clone, Copy)]
+pub struct Stats {
+    pub resettable: Counters,
+    pub lifetime: Counters,
+}
+
+impl Stats {
+    /**
+    Updates both sets of counters
+    */
+    fn record(&mut self, update: impl Fn(&mut Counters)) {
+        update(&mut self.resettable);
+        update(&mut self.lifetime);
+    }
+}
+
+/// The current counters
+static STATS: Mutex<RefCell<Stats>> = Mutex::new(RefCell::new(Stats {
+    resettable: Counters::ZERO,
+    lifetime: Counters::ZERO,
+}));
+
+/// true while the counters hold counts that have not been saved yet
+static DIRTY: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// time of the last save in milliseconds, None before the first save
+static LAST_SAVE_MS: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+fn record(update: impl Fn(&mut Counters)) {
+    interrupt::free(|cs| {
+        STATS.borrow(cs).borrow_mut().record(update);
+        DIRTY.borrow(cs).set(true);
+    })
+}
+
+/**
+Counts a game and its play time
+*/
+pub fn record_play(play_time_ms: u64) {
+    record(|counters| {
+        counters.plays = counters.plays.saturating_add(1);
+        counters.play_time_ms = counters.play_time_ms.saturating_add(play_time_ms);
+    })
+}
+
+/**
+Counts a strong grip
+*/
+pub fn record_strong_grip() {
+    record(|counters| counters.strong_grips = counters.strong_grips.saturating_add(1))
+}
+
+/**
+Counts a prize that has been won
+*/
+pub fn record_win() {
+    record(|counters| counters.wins = counters.wins.saturating_add(1))
+}
+
+/**
+Counts a coin, this is called by the coin acceptor interrupt
+*/
+pub fn record_coin() {
+    record(|counters| counters.coins = counters.coins.saturating_add(1))
+}
+
+/**
+Counts a fault by its kind
+*/
+pub fn record_fault(fault: Fault) {
+    record(|counters| {
+        let count = &mut counters.faults[fault.index()];
+        *count = count.saturating_add(1);
+    })
+}
+
+/**
+Gets the current counters
+*/
+pub fn current() -> Stats {
+    interrupt::free(|cs| *STATS.borrow(cs).borrow())
+}
+
+/**
+Loads the counters from the EEPROM, a record that does not load is reported and all counters start
+at zero
+*/
+pub fn load() {
+    let mut bytes = [0; SIZE];
+    if store::load(OFFSET, VERSION, &mut bytes) {
+        let mut reader = RecordReader::new(&bytes);
+        let stats = Stats {
+            resettable: Counters::decode(&mut reader),
+            lifetime: Counters::decode(&mut reader),
+        };
+        interrupt::free(|cs| *STATS.borrow(cs).borrow_mut() = stats);
+    } else {
+        report(Fault::InvalidStatistics);
+    }
+}
+
+/**
+Saves the counters to the EEPROM, only the bytes that changed are written
+*/
+pub fn save() {
+    let stats = interrupt::free(|cs| {
+        DIRTY.borrow(cs).set(false);
+        LAST_SAVE_MS.borrow(cs).set(Some(PrecisionTicker::now_ms()));
+        *STATS.borrow(cs).borrow()
+    });
+
+    let mut bytes = [0; SIZE];
+    let mut writer = RecordWriter::new(&mut bytes);
+    stats.resettable.encode(&mut writer);
+    stats.lifetime.encode(&mut writer);
+    store::save(OFFSET, VERSION, &bytes);
+    debug!("stats saved");
+}
+
+/**
+Saves the counters if they changed and the save interval has passed since the last save
+*/
+fn save_if_due() {
+    let due = interrupt::free(|cs| {
+        let dirty = DIRTY.borrow(cs).get();
+        let interval_passed = LAST_SAVE_MS.borrow(cs).get().map_or(true, |last| {
+            PrecisionTicker::now_ms() - last >= SAVE_INTERVAL_MS
+        });
+        dirty && interval_passed
+    });
+    if due {
+        save();
+    }
+}
+
+/**
+Task that saves the counters once they are due, it runs while nobody plays, since a save holds up
+the other tasks for a few milliseconds per changed byte
+*/
+pub async fn save_task() {
+    loop {
+        save_if_due();
+        delay_s(SAVE_CHECK_S).await;
+    }
+}
+
+/**
+Resets the resettable counters and saves the counters at once, the lifetime counters are kept
+*/
+pub fn reset() {
+    interrupt::free(|cs| STATS.borrow(cs).borrow_mut().resettable = Counters::ZERO);
+    save();
+}
+
+/**
+Prints both sets of counters on the console, the task waits for room on the console
+*/
+pub async fn print() {
+    let stats = current();
+    let (resettable, lifetime) = (&stats.resettable, &stats.lifetime);
+    serial::print(|line| ufmt::uwrite!(line, "stats: since reset / lifetime")).await;
+    serial::print(|line| ufmt::uwrite!(line, "plays: {} / {}", resettable.plays, lifetime.plays))
+        .await;
+    serial::print(|line| {
+        ufmt::uwrite!(
+            line,
+            "strong grips: {} / {}",
+            resettable.strong_grips,
+            lifetime.strong_grips
+        )
+    })
+    .await;
+    serial::print(|line| ufmt::uwrite!(line, "wins: {} / {}", resettable.wins, lifetime.wins))
+        .await;
+    serial::print(|line| {
+        ufmt::uwrite!(
+            line,
+            "payout permille: {} / {}",
+            resettable.payout_permille(),
+            lifetime.payout_permille()
+        )
+    })
+    .await;
+    serial::print(|line| ufmt::uwrite!(line, "coins: {} / {}", resettable.coins, lifetime.coins))
+        .await;
+    serial::print(|line| {
+        ufmt::uwrite!(
+            line,
+            "average play ms: {} / {}",
+            resettable.average_play_ms(),
+            lifetime.average_play_ms()
+        )
+    })
+    .await;
+    for (index, name) in fault::KIND_NAMES.iter().enumerate() {
+        serial::print(|line| {
+            ufmt::uwrite!(
+                line,
+                "fault {}: {} / {}",
+                *name,
+                resettable.faults[index],
+                lifetime.faults[index]
+            )
+        })
+        .await;
+    }
+}
@@ -1,21 +1,62 @@
+//! This module drives the stepper motors of the gantry and the pulley
 //!
-//!
-//!
+//! Every axis keeps track of its position in steps, where 0 is the home position at the limit
+//! switch. Clockwise steps move away from home, counter-clockwise steps move towards it.
+//! The y-axis is driven by two motors mounted mirrored, thus the second motor gets the inverted
+//! direction.
 
 use crate::channel::Receiver;
+use crate::input_switch::InputSwitch;
+use crate::settings;
 use crate::stepper::StepperDirection::{ClockWise, CounterClockWise, Idle};
 use crate::timer::delay_us;
-use arduino_hal::hal::port::{Dynamic, PA0, PA1, PA2, PA3, PA4, PA5};
+use arduino_hal::hal::port::Dynamic;
 use arduino_hal::port::mode::Output;
 use arduino_hal::port::Pin;
-use core::future::join;
+use futures::select_biased;
 use futures::FutureExt;
 
-use embedded_hal::digital::OutputPin;
-use futures::select_biased;
+/// ranges of the axes of our cabinet, used until the operator changes them
+pub const MAX_X_STEPS: i32 = 1000;
+pub const MAX_Y_STEPS: i32 = 1000;
+pub const MAX_Z_STEPS: i32 = 1200;
+
+/// half of the period of one step while the player moves the gantry at full speed
+const GANTRY_MIN_HALF_PERIOD_US: u16 = 1000;
+
+/// half of the period of one step while the player moves the gantry at the slowest speed
+const GANTRY_MAX_HALF_PERIOD_US: u16 = 4000;
+
+/**
+Settings of the axes
+max steps => range of each axis in steps from home
+min half period => half of the period of one step while the player moves the gantry at full speed
+max half period => half of the period of one step while the player moves the gantry at the slowest
+speed
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StepperConfig {
+    pub max_x_steps: i32,
+    pub max_y_steps: i32,
+    pub max_z_steps: i32,
+    pub min_half_period_us: u16,
+    pub max_half_period_us: u16,
+}
+
+impl Default for StepperConfig {
+    fn default() -> Self {
+        Self {
+            max_x_steps: MAX_X_STEPS,
+            max_y_steps: MAX_Y_STEPS,
+            max_z_steps: MAX_Z_STEPS,
+            min_half_period_us: GANTRY_MIN_HALF_PERIOD_US,
+            max_half_period_us: GANTRY_MAX_HALF_PERIOD_US,
+        }
+    }
+}
 
-const MAX_X_STEPS: i32 = 1000;
-const MAX_Y_STEPS: i32 = 1000;
+/// the fastest speed of a stepper command
+pub const FULL_SPEED: u8 = 255;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum StepperDirection {
@@ -24,109 +65,203 @@ pub enum StepperDirection {
     CounterClockWise,
 }
 
+/**
+Command for a gantry axis
+direction => the direction the axis moves in
+speed => proportional speed, 0 is the slowest and FULL_SPEED the fastest step rate
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StepperCommand {
+    pub direction: StepperDirection,
+    pub speed: u8,
+}
 
-pub async fn x_gantry (
-    mut receiver: Receiver<'_,StepperDirection>,
-    x_stepper_direction: &mut Pin<Output, PA1>,
-    x_stepper_pulse: &mut Pin<Output, PA0>
-) {
-    let mut steps = 0;
-    let mut stepper_direction = Idle;
-    loop {
-        match stepper_direction {
-            Idle => {
-                stepper_direction = receiver.receive().await;
-            },
-            ClockWise => {
-                select_biased! {
-                    stepper_direction = receiver.receive().fuse() => {},
-                    _ = async {
-                        if steps > MAX_X_STEPS {
-                            return;
-                        }
-                        x_stepper_pulse.set_high();
-                        delay_us(1000).await;
-                        x_stepper_pulse.set_low();
-                        delay_us(1000).await;
-                        steps += 1;
-                    }.fuse() => {}
-                }
-            },
-            CounterClockWise => {
-                select_biased! {
-                    stepper_direction = receiver.receive().fuse() => {},
-                    _ = async {
-                        if steps <= 0 {
-                            return;
-                        }
-                        x_stepper_direction.set_high();
-                        x_stepper_pulse.set_high();
-                        delay_us(1000).await;
-                        x_stepper_pulse.set_low();
-                        delay_us(1000).await;
-                        x_stepper_direction.set_low();
-                        steps -= 1;
-                    }.fuse() => {}
-                }
+impl StepperCommand {
+    pub const IDLE: StepperCommand = StepperCommand::new(Idle, 0);
+
+    pub const fn new(direction: StepperDirection, speed: u8) -> Self {
+        Self { direction, speed }
+    }
+
+    /**
+    Gets the half of the period of one step for the speed of the command
+    */
+    fn half_period_us(&self) -> u16 {
+        let config = settings::current().stepper;
+        let range = config
+            .max_half_period_us
+            .saturating_sub(config.min_half_period_us) as u32;
+        config.max_half_period_us - (range * self.speed as u32 / FULL_SPEED as u32) as u16
+    }
+}
+
+/**
+One axis of the machine with its motor pins and its position
+*/
+pub struct Axis {
+    pulse: Pin<Output, Dynamic>,
+    direction: Pin<Output, Dynamic>,
+    /// pulse and direction pin of a second motor that is mounted mirrored
+    mirrored: Option<(Pin<Output, Dynamic>, Pin<Output, Dynamic>)>,
+    position: i32,
+    max_steps: i32,
+}
+
+impl Axis {
+    pub fn new(
+        pulse: Pin<Output, Dynamic>,
+        direction: Pin<Output, Dynamic>,
+        max_steps: i32,
+    ) -> Self {
+        Self {
+            pulse,
+            direction,
+            mirrored: None,
+            position: 0,
+            max_steps,
+        }
+    }
+
+    pub fn with_mirrored(
+        pulse: Pin<Output, Dynamic>,
+        direction: Pin<Output, Dynamic>,
+        mirrored_pulse: Pin<Output, Dynamic>,
+        mirrored_direction: Pin<Output, Dynamic>,
+        max_steps: i32,
+    ) -> Self {
+        Self {
+            pulse,
+            direction,
+            mirrored: Some((mirrored_pulse, mirrored_direction)),
+            position: 0,
+            max_steps,
+        }
+    }
+
+    /**
+    Gets the current position in steps from home
+    */
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    /**
+    Gets the range of the axis in steps from home
+    */
+    pub fn max_steps(&self) -> i32 {
+        self.max_steps
+    }
+
+    /**
+    Sets the range of the axis in steps from home
+    */
+    pub fn set_max_steps(&mut self, max_steps: i32) {
+        self.max_steps = max_steps;
+    }
+
+    /**
+    Makes a single step in the given direction
+
+    RETURNS: false if the step would leave the range between home and max steps
+    */
+    pub async fn step(&mut self, direction: StepperDirection, half_period_us: u16) -> bool {
+        let allowed = match direction {
+            Idle => false,
+            ClockWise => self.position < self.max_steps,
+            CounterClockWise => self.position > 0,
+        };
+        if allowed {
+            self.single_step(direction, half_period_us).await;
+        }
+        allowed
+    }
+
+    /**
+    Moves the axis to the target position, the target is clamped to the range of the axis
+    */
+    pub async fn move_to(&mut self, target: i32, half_period_us: u16) {
+        let target = target.clamp(0, self.max_steps);
+        while self.position != target {
+            let direction = if self.position < target {
+                ClockWise
+            } else {
+                CounterClockWise
+            };
+            self.step(direction, half_period_us).await;
+        }
+    }
+
+    /**
+    Moves the axis towards home until its limit switch is pressed and resets the position
+
+    The switch is sampled before every step and stops the axis on the first press, a bouncing
+    contact only matters when the switch is released again
+    */
+    pub async fn home(&mut self, limit_switch: &InputSwitch, half_period_us: u16) {
+        // the position is unknown until home is reached, so the soft limits do not apply
+        while !limit_switch.is_pressed() {
+            self.single_step(CounterClockWise, half_period_us).await;
+        }
+        self.position = 0;
+    }
+
+    async fn single_step(&mut self, direction: StepperDirection, half_period_us: u16) {
+        // a cancelled step might have left the pulse pin high
+        self.pulse.set_low();
+        if let Some((mirrored_pulse, _)) = self.mirrored.as_mut() {
+            mirrored_pulse.set_low();
+        }
+
+        let clockwise = direction == ClockWise;
+        if clockwise {
+            self.direction.set_low();
+        } else {
+            self.direction.set_high();
+        }
+        if let Some((_, mirrored_direction)) = self.mirrored.as_mut() {
+            if clockwise {
+                mirrored_direction.set_high();
+            } else {
+                mirrored_direction.set_low();
             }
         }
+
+        // the driver steps on the rising edge
+        self.pulse.set_high();
+        if let Some((mirrored_pulse, _)) = self.mirrored.as_mut() {
+            mirrored_pulse.set_high();
+        }
+        self.position += if clockwise { 1 } else { -1 };
+        delay_us(half_period_us).await;
+        self.pulse.set_low();
+        if let Some((mirrored_pulse, _)) = self.mirrored.as_mut() {
+            mirrored_pulse.set_low();
+        }
+        delay_us(half_period_us).await;
     }
 }
 
-pub async fn y_gantry (
-    mut receiver: Receiver<'_,StepperDirection>,
-    y_stepper_direction: &mut Pin<Output, PA3>,
-    y_stepper_pulse: &mut Pin<Output, PA2>,
-    y_stepper_direction_inverted: &mut Pin<Output, PA5>,
-    y_stepper_pulse_inverted: &mut Pin<Output, PA4>
-) {
-    let mut steps = 0;
+/**
+Moves a gantry axis as commanded by the joystick until another command is received
+*/
+pub async fn gantry(mut receiver: Receiver<'_, StepperCommand>, axis: &mut Axis) {
+    let mut command = StepperCommand::IDLE;
     loop {
-        let mut stepper_direction = Idle;
-        loop {
-            match stepper_direction {
-                Idle => {
-                    stepper_direction = receiver.receive().await;
-                },
-                ClockWise => {
-                    select_biased! {
-                    stepper_direction = receiver.receive().fuse() => {},
-                    _ = async {
-                        if steps > MAX_X_STEPS {
-                            return;
-                        }
-                        y_stepper_direction_inverted.set_high();
-                        y_stepper_pulse.set_high();
-                        y_stepper_pulse_inverted.set_high();
-                        delay_us(1000).await;
-                        y_stepper_pulse.set_low();
-                        y_stepper_pulse_inverted.set_low();
-                        delay_us(1000).await;
-                        y_stepper_direction_inverted.set_low();
-                        steps += 1;
-                    }.fuse() => {}
-                }
-                },
-                CounterClockWise => {
-                    select_biased! {
-                    stepper_direction = receiver.receive().fuse() => {},
-                    _ = async {
-                        if steps <= 0 {
-                            return;
-                        }
-                        y_stepper_direction.set_high();
-                        y_stepper_pulse.set_high();
-                        y_stepper_pulse_inverted.set_high();
-                        delay_us(1000).await;
-                        y_stepper_pulse.set_low();
-                        y_stepper_pulse_inverted.set_low();
-                        delay_us(1000).await;
-                        y_stepper_direction.set_low();
-                        steps -= 1;
-                    }.fuse() => {}
+        match command.direction {
+            Idle => {
+                command = receiver.receive().await;
+            }
+            direction => {
+                let mut moved = true;
+                select_biased! {
+                    new_command = receiver.receive().fuse() => command = new_command,
+                    step = axis.step(direction, command.half_period_us()).fuse() => moved = step,
                 }
+                // the axis reached its end, wait until the player changes direction
+                if !moved {
+                    command = receiver.receive().await;
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,236 @@
+//! This module keeps records in the EEPROM of the ATmega2560
+//!
+//! A record is stored at a fixed offset and framed by its version, its length and a checksum
+//!
+//! version (1 byte) | length (1 byte) | payload (length bytes) | fletcher-16 checksum (2 bytes)
+//!
+//! A record only loads if all of them match, so a record written by an older firmware, a fresh
+//! EEPROM or a save torn by a power loss are all detected. The EEPROM endures about 100000 writes
+//! per byte, thus a save only writes the bytes that actually changed. Every byte takes a few
+//! milliseconds to write, the store waits for the last write with the interrupts enabled and only
+//! blocks them while it starts the next one, so the timers and the console keep running during a
+//! save.
+//!
+//! How to use:
+//! Hand the EEPROM over during setup, encode a record with a RecordWriter and decode it with a
+//! RecordReader
+
+use arduino_hal::Eeprom;
+use avr_device::atmega2560::EEPROM as EepromRegisters;
+use avr_device::interrupt;
+use core::cell::RefCell;
+
+use crate::Mutex;
+
+/// bytes of the version and the length in front of the payload
+const HEADER_SIZE: u16 = 2;
+
+/// bytes of the checksum behind the payload
+const CHECKSUM_SIZE: u16 = 2;
+
+/// The EEPROM, None until it has been handed over
+static EEPROM: Mutex<RefCell<Option<Eeprom>>> = Mutex::new(RefCell::new(None));
+
+/**
+Hands the EEPROM over to the store
+*/
+pub fn init(eeprom: Eeprom) {
+    interrupt::free(|cs| *EEPROM.borrow(cs).borrow_mut() = Some(eeprom))
+}
+
+/**
+Gets the bytes a record with the given payload takes in the EEPROM
+*/
+pub const fn record_size(payload_len: usize) -> u16 {
+    HEADER_SIZE + payload_len as u16 + CHECKSUM_SIZE
+}
+
+/**
+Fletcher-16 checksum over the header and the payload
+*/
+fn checksum(version: u8, payload: &[u8]) -> u16 {
+    let mut sum1: u16 = 0;
+    let mut sum2: u16 = 0;
+    for byte in [version, payload.len() as u8].iter().chain(payload) {
+        sum1 = (sum1 + *byte as u16) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+}
+
+/**
+Busy waits until the EEPROM has finished the last write, the interrupts stay enabled meanwhile
+
+A read or a write has to wait for the last write anyway, waiting here keeps the critical sections
+of the HAL short
+*/
+fn wait_ready() {
+    // SAFETY: the write enable bit is only read, writing the registers is left to the Eeprom
+    let registers = unsafe { &*EepromRegisters::ptr() };
+    while registers.eecr.read().eepe().bit_is_set() {}
+}
+
+fn read_byte(offset: u16) -> u8 {
+    wait_ready();
+    interrupt::free(|cs| {
+        EEPROM
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .map_or(0xFF, |eeprom| eeprom.read_byte(offset))
+    })
+}
+
+/**
+Writes a single byte, a byte that already holds the value is not written to spare the EEPROM
+*/
+fn update_byte(offset: u16, value: u8) {
+    wait_ready();
+    // the write only gets started, the EEPROM finishes it while the interrupts are enabled again
+    interrupt::free(|cs| {
+        if let Some(eeprom) = EEPROM.borrow(cs).borrow_mut().as_mut() {
+            if eeprom.read_byte(offset) != value {
+                eeprom.write_byte(offset, value);
+            }
+        }
+    })
+}
+
+/**
+Loads the payload of a record
+
+RETURNS: false if the record has another version or length, or its checksum does not match
+*/
+pub fn load(offset: u16, version: u8, payload: &mut [u8]) -> bool {
+    if read_byte(offset) != version || read_byte(offset + 1) != payload.len() as u8 {
+        return false;
+    }
+
+    let payload_offset = offset + HEADER_SIZE;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte = read_byte(payload_offset + index as u16);
+    }
+
+    let checksum_offset = payload_offset + payload.len() as u16;
+    let stored = u16::from_le_bytes([read_byte(checksum_offset), read_byte(checksum_offset + 1)]);
+    stored == checksum(version, payload)
+}
+
+/**
+Saves the payload of a record, only the bytes that changed are written
+
+The interrupts are only blocked while a write of a byte is started, never while the EEPROM is busy
+writing
+*/
+pub fn save(offset: u16, version: u8, payload: &[u8]) {
+    update_byte(offset, version);
+    update_byte(offset + 1, payload.len() as u8);
+
+    let payload_offset = offset + HEADER_SIZE;
+    for (index, byte) in payload.iter().enumerate() {
+        update_byte(payload_offset + index as u16, *byte);
+    }
+
+    let checksum_offset = payload_offset + payload.len() as u16;
+    let [low, high] = checksum(version, payload).to_le_bytes();
+    update_byte(checksum_offset, low);
+    update_byte(checksum_offset + 1, high);
+}
+
+/**
+Encodes the fields of a record one after another, all values are little endian
+*/
+pub struct RecordWriter<'a> {
+    bytes: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> RecordWriter<'a> {
+    pub fn new(bytes: &'a mut [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes[self.position] = value;
+        self.position += 1;
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        for byte in value.to_le_bytes() {
+            self.u8(byte);
+        }
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        for byte in value.to_le_bytes() {
+            self.u8(byte);
+        }
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        for byte in value.to_le_bytes() {
+            self.u8(byte);
+        }
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+}
+
+/**
+Decodes the fields of a record in the order they have been encoded
+*/
+pub struct RecordReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        let value = self.bytes[self.position];
+        self.position += 1;
+        value
+    }
+
+    /**
+    Decodes a bool
+
+    RETURNS: None if the byte is neither 0 nor 1
+    */
+    pub fn bool(&mut self) -> Option<bool> {
+        match self.u8() {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes([self.u8(), self.u8(), self.u8(), self.u8()])
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        for byte in bytes.iter_mut() {
+            *byte = self.u8();
+        }
+        u64::from_le_bytes(bytes)
+    }
+
+    pub fn i32(&mut self) -> i32 {
+        self.u32() as i32
+    }
+}
@@ -0,0 +1,124 @@
+//! This module watches the joystick and the UI buttons for stuck switches
+//!
+//! A stuck contact looks exactly like a player holding the switch, a stuck joystick switch drives
+//! its axis into the end and keeps it there. A switch that is continuously active for longer than
+//! the threshold is reported as a fault and ignored for the remainder of the game, so the player
+//! can still play with the remaining switches.
+//!
+//! The threshold is part of the settings.
+//!
+//! How to use:
+//! Run the stuck switch task alongside the tasks of a running game, it clears the ignored switches
+//! when it starts
+
+use avr_device::interrupt;
+use core::cell::Cell;
+
+use crate::button::Button;
+use crate::fault::{report, Fault};
+use crate::input_switch::InputSwitch;
+use crate::joystick::{self, JoystickDirection};
+use crate::settings;
+use crate::timer::{delay_ms, PrecisionTicker};
+use crate::Mutex;
+
+/// Threshold of the default settings, longer than any sensible move across the gantry
+pub const DEFAULT_THRESHOLD_MS: u16 = 15000;
+
+/// time between two samples of the switches
+const CHECK_INTERVAL_MS: u16 = 100;
+
+/// a bit for every switch that is ignored for the remainder of the game, indexed by Switch::index
+static IGNORED: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/**
+All switches that are watched
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Switch {
+    Joystick(JoystickDirection),
+    Button(Button),
+}
+
+/// all watched switches in the order of their index
+const SWITCHES: [Switch; 6] = [
+    Switch::Joystick(JoystickDirection::RIGHT),
+    Switch::Joystick(JoystickDirection::LEFT),
+    Switch::Joystick(JoystickDirection::FORWARD),
+    Switch::Joystick(JoystickDirection::BACKWARD),
+    Switch::Button(Button::Start),
+    Switch::Button(Button::End),
+];
+
+impl Switch {
+    fn index(&self) -> usize {
+        match self {
+            Switch::Joystick(direction) => *direction as usize,
+            Switch::Button(Button::Start) => 4,
+            Switch::Button(Button::End) => 5,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Switch::Joystick(JoystickDirection::RIGHT) => "joystick right",
+            Switch::Joystick(JoystickDirection::LEFT) => "joystick left",
+            Switch::Joystick(JoystickDirection::FORWARD) => "joystick forward",
+            Switch::Joystick(JoystickDirection::BACKWARD) => "joystick backward",
+            Switch::Button(Button::Start) => "start button",
+            Switch::Button(Button::End) => "end button",
+        }
+    }
+
+    fn input(&self) -> InputSwitch {
+        match self {
+            Switch::Joystick(direction) => joystick::input(*direction),
+            Switch::Button(button) => button.input(),
+        }
+    }
+}
+
+/**
+Checks whether a switch has been found stuck in the current game
+*/
+pub fn is_ignored(switch: Switch) -> bool {
+    interrupt::free(|cs| IGNORED.borrow(cs).get() & (1 << switch.index()) != 0)
+}
+
+fn ignore(switch: Switch) {
+    interrupt::free(|cs| {
+        let ignored = IGNORED.borrow(cs);
+        ignored.set(ignored.get() | (1 << switch.index()));
+    })
+}
+
+/**
+Task that samples the switches and ignores every switch that is active for too long
+*/
+pub async fn stuck_switch_task() {
+    // a new game gives every switch a new chance
+    interrupt::free(|cs| IGNORED.borrow(cs).set(0));
+
+    let mut active_since: [Option<u64>; 6] = [None; 6];
+    loop {
+        let now_ms = PrecisionTicker::now_ms();
+        let threshold_ms = settings::current().stuck_threshold_ms as u64;
+        for switch in SWITCHES {
+            let since = &mut active_since[switch.index()];
+            if is_ignored(switch) || !switch.input().is_pressed() {
+                *since = None;
+                continue;
+            }
+
+            let since = *since.get_or_insert(now_ms);
+            if now_ms - since >= threshold_ms {
+                ignore(switch);
+                report(Fault::StuckSwitch(switch));
+                // the waiting task has to drop the stuck switch from its state
+                switch.input().notify();
+            }
+        }
+
+        delay_ms(CHECK_INTERVAL_MS).await;
+    }
+}
@@ -3,8 +3,8 @@
 //! In our project we need three timers
 //!
 //! t0: is our precision timer it handles pulse generation for our servo motors
-//! t1: is our generic timer which handles the automatic finish after 30 seconds
-//! t2: will be used for PWM control of our servo motor, but we will use SimplePWM from the HAL
+//! t1: is our generic timer which handles the automatic finish once the play time is over
+//! t3: generates the 50 Hz frame for our claw servo, it is configured by the servo module
 //!
 //! Both timers are built in the way that we could expand the project, ex. when more components would
 //! need a timer like LEDs or a count-down clock.
@@ -18,6 +18,7 @@ use core::cell::{Cell, RefCell, RefMut};
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
+use futures::{select_biased, FutureExt};
 use heapless::binary_heap::{BinaryHeap, Min};
 
 use crate::executor::{wake_task, ExtWaker};
@@ -39,21 +40,55 @@ static G_QUEUE: Mutex<RefCell<BinaryHeap<(u64, usize), Min, 4>>> =
 static G_TICK_COUNTER: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
 
 /// A variable tick incrementer
-static G_TICK_INCREMENT: Mutex<Cell<u64>> = Mutex::new(Cell::new(65535));
+static G_TICK_INCREMENT: Mutex<Cell<u64>> = Mutex::new(Cell::new(62500));
+
+/// The shortest interval we schedule, shorter intervals could be missed due to the ISR latency
+const G_MIN_INTERVAL: u64 = 8;
 
 /**
 Constant conversion that convert seconds to generic ticks
 Max register: 65535
 Our max 62'500
-When our max match occurs every four seconds
+When our max match occurs every second
 */
-const fn s_to_generic_ticks(s: u8) -> u64 {
+pub const fn s_to_generic_ticks(s: u8) -> u64 {
     62500 * s as u64
 }
 
+/**
+State of a timer
+Init => the timer has not been polled yet
+Waiting => the timer is registered for the task in its queue
+Done => the timer has expired and is not in its queue anymore
+*/
 pub enum TimerState {
     Init,
-    Waiting,
+    Waiting(usize),
+    Done,
+}
+
+/**
+Removes the entry of a timer from its queue, so it neither takes up a slot nor wakes a task of a
+later state
+
+The heap can not remove arbitrary entries, so it is rebuilt without the entry. An entry the
+interrupt has already taken out leaves the heap untouched.
+*/
+fn remove_entry<const N: usize>(queue: &mut BinaryHeap<(u64, usize), Min, N>, entry: (u64, usize)) {
+    if !queue.iter().any(|queued| *queued == entry) {
+        return;
+    }
+    let mut kept = BinaryHeap::new();
+    let mut removed = false;
+    while let Some(queued) = queue.pop() {
+        if !removed && queued == entry {
+            removed = true;
+        } else {
+            // the rebuilt heap has the same capacity, so every other entry fits
+            let _ = kept.push(queued);
+        }
+    }
+    *queue = kept;
 }
 
 /**
@@ -67,10 +102,12 @@ pub struct GenericTicker {
 impl GenericTicker {
     pub fn init(tc1: TC1) {
         // write counter max to register
-        tc1.ocr1a.write(|w| w.bits(62500));
-        // set flag to only count to max and set CTC mode
+        tc1.ocr1a.write(|w| w.bits(G_TICKER.max - 1));
+        // set flag to only count to max and set CTC mode, mode 4 only sets WGM12 which is the
+        // lower bit of the WGM1 field in TCCR1B
+        tc1.tccr1a.write(|w| w.wgm1().bits(0));
         tc1.tccr1b.write(|w| {
-            w.wgm1().bits(4);
+            w.wgm1().bits(0b01);
             w.cs1().prescale_256()
         });
         // enable CTC mode interrupt
@@ -80,14 +117,31 @@ impl GenericTicker {
         interrupt::free(|cs| {
             G_TICKER.tc1.borrow(cs).replace(Some(tc1));
             G_TICK_COUNTER.borrow(cs).set(0);
+            G_TICK_INCREMENT.borrow(cs).set(G_TICKER.max as u64);
         })
     }
 
     /**
-    Gets the current generic tick count
+    Gets the current generic tick count, including the ticks of the running interval
     */
     pub fn now() -> u64 {
-        interrupt::free(|cs| G_TICK_COUNTER.borrow(cs).get())
+        interrupt::free(|cs| {
+            let counter = G_TICK_COUNTER.borrow(cs).get();
+            match G_TICKER.tc1.borrow(cs).borrow().as_ref() {
+                Some(tc1) => {
+                    let ticks = tc1.tcnt1.read().bits() as u64;
+                    // a compare match happened but the interrupt has not been handled yet
+                    if tc1.tifr1.read().ocf1a().bit_is_set() {
+                        counter
+                            + G_TICK_INCREMENT.borrow(cs).get()
+                            + tc1.tcnt1.read().bits() as u64
+                    } else {
+                        counter + ticks
+                    }
+                }
+                None => counter,
+            }
+        })
     }
 }
 
@@ -108,24 +162,21 @@ impl GenericTimer {
         // also we need some shared variables
         interrupt::free(|cs| {
             let mut queue = G_QUEUE.borrow(cs).borrow_mut();
-            let is_first = if let Some((next_timer, _)) = queue.peek() {
-                self.end_ticks < *next_timer
-            } else {
-                true
-            };
             if queue.push((self.end_ticks, task)).is_err() {
                 panic!("Queue full")
             }
-            // if it is the first element queue for wakeup
-            if is_first {
-                let ticks = G_TICK_COUNTER.borrow(cs).get();
+
+            // if the timer ends before the next compare match, pull the compare match forward
+            let tc1 = G_TICKER.tc1.borrow(cs).borrow();
+            if let Some(tc1) = tc1.as_ref() {
+                let counter = G_TICK_COUNTER.borrow(cs).get();
                 let increment_c = G_TICK_INCREMENT.borrow(cs);
-                schedule_generic_wakeup(
-                    queue,
-                    G_TICKER.tc1.borrow(cs).borrow_mut(),
-                    ticks,
-                    increment_c,
-                )
+                let elapsed = tc1.tcnt1.read().bits() as u64;
+                let remainder = self.end_ticks.saturating_sub(counter);
+                if remainder < increment_c.get() && remainder >= elapsed + G_MIN_INTERVAL {
+                    tc1.ocr1a.write(|w| w.bits(remainder as u16 - 1));
+                    increment_c.set(remainder);
+                }
             }
         })
     }
@@ -136,17 +187,37 @@ impl Future for GenericTimer {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.state {
             TimerState::Init => {
-                self.register(cx.waker().task());
-                self.state = TimerState::Waiting;
+                // the timer starts counting once it is polled for the first time
+                self.end_ticks += GenericTicker::now();
+                let task = cx.waker().task();
+                self.register(task);
+                self.state = TimerState::Waiting(task);
                 Poll::Pending
             }
-            TimerState::Waiting => {
+            TimerState::Waiting(task) => {
                 if GenericTicker::now() >= self.end_ticks {
+                    // the task can be polled after the end but before the compare match is handled
+                    interrupt::free(|cs| {
+                        remove_entry(&mut G_QUEUE.borrow(cs).borrow_mut(), (self.end_ticks, task))
+                    });
+                    self.state = TimerState::Done;
                     Poll::Ready(())
                 } else {
                     Poll::Pending
                 }
             }
+            TimerState::Done => Poll::Ready(()),
+        }
+    }
+}
+
+impl Drop for GenericTimer {
+    fn drop(&mut self) {
+        // only a timer that has not expired is still in the queue
+        if let TimerState::Waiting(task) = self.state {
+            interrupt::free(|cs| {
+                remove_entry(&mut G_QUEUE.borrow(cs).borrow_mut(), (self.end_ticks, task))
+            })
         }
     }
 }
@@ -159,40 +230,26 @@ pub async fn delay_s(seconds: u8) {
 }
 
 /**
-Add a new timer to the generic queue
+Wakes all expired generic timers and schedules the next compare match
 */
 fn schedule_generic_wakeup(
     mut queue: RefMut<BinaryHeap<(u64, usize), Min, 4>>,
-    mut tc1: RefMut<Option<TC1>>,
+    tc1: &TC1,
     counter: u64,
     increment_c: &Cell<u64>,
 ) {
-    // take first of queue
-    // if first end tick - current tick <= 65535 add set ticker delta and modify increment
+    let mut interval = G_TICKER.max as u64;
     while let Some((end_ticks, task)) = queue.peek() {
-        let remainder: u64 = *end_ticks - counter;
-
-        if remainder <= 65535 {
-            // if ticks are near 0 another interrupt is not necessary => Wake up task immediately
-            if remainder <= 10 {
-                wake_task(*task);
-
-                // remove timer from queue
-                queue.pop();
-            } else {
-                // create a timed interrupt for the remaining time
-                tc1.as_mut()
-                    .unwrap()
-                    .ocr1a
-                    .write(|w| w.bits(remainder as u16));
-
-                // update the increment amount
-                increment_c.set(remainder);
-                // new timer has been scheduled break the while loop
-                break;
-            }
+        if *end_ticks <= counter {
+            wake_task(*task);
+            queue.pop();
+        } else {
+            interval = (*end_ticks - counter).clamp(G_MIN_INTERVAL, G_TICKER.max as u64);
+            break;
         }
     }
+    tc1.ocr1a.write(|w| w.bits(interval as u16 - 1));
+    increment_c.set(interval);
 }
 
 /**
@@ -204,14 +261,11 @@ fn TIMER1_COMPA() {
     interrupt::free(|cs| {
         let counter_c = G_TICK_COUNTER.borrow(cs);
         let increment_c = G_TICK_INCREMENT.borrow(cs);
-        let counter = counter_c.get() + G_TICK_INCREMENT.borrow(cs).get();
+        let counter = counter_c.get() + increment_c.get();
         counter_c.set(counter);
-        schedule_generic_wakeup(
-            G_QUEUE.borrow(cs).borrow_mut(),
-            G_TICKER.tc1.borrow(cs).borrow_mut(),
-            counter,
-            increment_c,
-        )
+        if let Some(tc1) = G_TICKER.tc1.borrow(cs).borrow().as_ref() {
+            schedule_generic_wakeup(G_QUEUE.borrow(cs).borrow_mut(), tc1, counter, increment_c)
+        }
     })
 }
 
@@ -220,10 +274,17 @@ Constant conversion that convert microseconds to precision ticks
 
 250 ticks equals one millisecond
 
-Min is 1 Tick which is 2.5 microseconds
+Min is 1 Tick which is 4 microseconds
 */
-const fn us_to_p_ticks(us: u16) -> u16 {
-    250 * us / 1000
+const fn us_to_p_ticks(us: u16) -> u64 {
+    us as u64 / 4
+}
+
+/**
+Constant conversion that convert milliseconds to precision ticks
+*/
+const fn ms_to_p_ticks(ms: u16) -> u64 {
+    250 * ms as u64
 }
 
 /// declare static precision ticker
@@ -233,12 +294,18 @@ static P_TICKER: PrecisionTicker = PrecisionTicker {
 };
 
 /// binary heap that acts as the priority queue for our Precision timers
-static P_QUEUE: Mutex<RefCell<BinaryHeap<(u64, usize), Min, 8>>> =
+static P_QUEUE: Mutex<RefCell<BinaryHeap<(u64, usize), Min, 16>>> =
     Mutex::new(RefCell::new(BinaryHeap::new()));
 
 /// Keep track of current precision tick count
 static P_TICK_COUNTER: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
 
+/// The amount of ticks until the next compare match, equals the value of OCR0A + 1
+static P_TICK_INCREMENT: Mutex<Cell<u64>> = Mutex::new(Cell::new(250));
+
+/// The shortest interval we schedule, shorter intervals could be missed due to the ISR latency
+const P_MIN_INTERVAL: u64 = 8;
+
 /**
 Ticker to generate Ticker with microsecond precision used internally to generate Timer Events with
 microsecond precision.
@@ -259,40 +326,162 @@ impl PrecisionTicker {
     pub fn init(tc0: TC0) {
         // enable CTC (clear timer on compare match)
         tc0.tccr0a.write(|w| w.wgm0().ctc());
+        // write counter max to register
+        tc0.ocr0a.write(|w| w.bits(P_TICKER.max - 1));
+        // enable CTC mode interrupt
+        tc0.timsk0.write(|w| w.ocie0a().set_bit());
         // choose the prescaler of the counter register
         tc0.tccr0b.write(|w| w.cs0().prescale_64());
 
         // replace tc0
         interrupt::free(|cs| {
             P_TICKER.tc0.borrow(cs).replace(Some(tc0));
+            P_TICK_COUNTER.borrow(cs).set(0);
+            P_TICK_INCREMENT.borrow(cs).set(P_TICKER.max as u64);
         })
     }
+
+    /**
+    Gets the current precision tick count
+
+    Unlike the generic ticker the count also includes the ticks of the running interval
+    */
+    pub fn now() -> u64 {
+        interrupt::free(|cs| {
+            let counter = P_TICK_COUNTER.borrow(cs).get();
+            match P_TICKER.tc0.borrow(cs).borrow().as_ref() {
+                Some(tc0) => {
+                    let ticks = tc0.tcnt0.read().bits() as u64;
+                    // a compare match happened but the interrupt has not been handled yet
+                    if tc0.tifr0.read().ocf0a().bit_is_set() {
+                        counter
+                            + P_TICK_INCREMENT.borrow(cs).get()
+                            + tc0.tcnt0.read().bits() as u64
+                    } else {
+                        counter + ticks
+                    }
+                }
+                None => counter,
+            }
+        })
+    }
+
+    /**
+    Gets the milliseconds since the precision ticker has been initialized
+    */
+    pub fn now_ms() -> u64 {
+        Self::now() / 250
+    }
 }
 
 pub struct PrecisionTimer {
-    end_ticks: u16,
+    end_ticks: u64,
+    state: TimerState,
 }
 
 impl PrecisionTimer {
     pub fn new(microseconds: u16) -> Self {
         Self {
             end_ticks: us_to_p_ticks(microseconds),
+            state: TimerState::Init,
+        }
+    }
+
+    pub fn from_ms(milliseconds: u16) -> Self {
+        Self {
+            end_ticks: ms_to_p_ticks(milliseconds),
+            state: TimerState::Init,
         }
     }
 
     fn register(&self, task: usize) {
-        interrupt::free(|cs| {})
+        // create critical section as no interrupts should happen during registering of a timer
+        interrupt::free(|cs| {
+            let mut queue = P_QUEUE.borrow(cs).borrow_mut();
+            if queue.push((self.end_ticks, task)).is_err() {
+                panic!("Queue full")
+            }
+
+            // if the timer ends before the next compare match, pull the compare match forward
+            let tc0 = P_TICKER.tc0.borrow(cs).borrow();
+            if let Some(tc0) = tc0.as_ref() {
+                let counter = P_TICK_COUNTER.borrow(cs).get();
+                let increment_c = P_TICK_INCREMENT.borrow(cs);
+                let elapsed = tc0.tcnt0.read().bits() as u64;
+                let remainder = self.end_ticks.saturating_sub(counter);
+                if remainder < increment_c.get() && remainder >= elapsed + P_MIN_INTERVAL {
+                    tc0.ocr0a.write(|w| w.bits(remainder as u8 - 1));
+                    increment_c.set(remainder);
+                }
+            }
+        })
     }
 }
 
 impl Future for PrecisionTimer {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        todo!()
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.state {
+            TimerState::Init => {
+                // the timer starts counting once it is polled for the first time
+                self.end_ticks += PrecisionTicker::now();
+                let task = cx.waker().task();
+                self.register(task);
+                self.state = TimerState::Waiting(task);
+                Poll::Pending
+            }
+            TimerState::Waiting(task) => {
+                if PrecisionTicker::now() >= self.end_ticks {
+                    // the task can be polled after the end but before the compare match is handled
+                    interrupt::free(|cs| {
+                        remove_entry(&mut P_QUEUE.borrow(cs).borrow_mut(), (self.end_ticks, task))
+                    });
+                    self.state = TimerState::Done;
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }
+            TimerState::Done => Poll::Ready(()),
+        }
+    }
+}
+
+impl Drop for PrecisionTimer {
+    fn drop(&mut self) {
+        // timers raced by with_timeout or select_biased! are dropped before they expire, an expired
+        // timer is Done and not in the queue anymore
+        if let TimerState::Waiting(task) = self.state {
+            interrupt::free(|cs| {
+                remove_entry(&mut P_QUEUE.borrow(cs).borrow_mut(), (self.end_ticks, task))
+            })
+        }
     }
 }
-fn schedule_precision_wakeup() {}
+
+/**
+Wakes all expired precision timers and schedules the next compare match
+*/
+fn schedule_precision_wakeup(
+    mut queue: RefMut<BinaryHeap<(u64, usize), Min, 16>>,
+    tc0: &TC0,
+    counter: u64,
+    increment_c: &Cell<u64>,
+) {
+    let mut interval = P_TICKER.max as u64;
+    while let Some((end_ticks, task)) = queue.peek() {
+        if *end_ticks <= counter {
+            wake_task(*task);
+            queue.pop();
+        } else {
+            interval = (*end_ticks - counter).clamp(P_MIN_INTERVAL, P_TICKER.max as u64);
+            break;
+        }
+    }
+    tc0.ocr0a.write(|w| w.bits(interval as u8 - 1));
+    increment_c.set(interval);
+}
 
 /**
 Public function that delays something for n us
@@ -301,11 +490,38 @@ pub async fn delay_us(us: u16) {
     PrecisionTimer::new(us).await
 }
 
+/**
+Public function that delays something for n ms
+*/
+pub async fn delay_ms(ms: u16) {
+    PrecisionTimer::from_ms(ms).await
+}
+
+/**
+Public function that races a future against a delay of n ms
+
+RETURNS: the output of the future or None if the delay ran out first
+*/
+pub async fn with_timeout<F: Future>(ms: u16, future: F) -> Option<F::Output> {
+    select_biased! {
+        output = future.fuse() => Some(output),
+        _ = delay_ms(ms).fuse() => None,
+    }
+}
+
 /**
 Interrupt triggered at least every millisecond
 */
 #[avr_device::interrupt(atmega2560)]
 #[allow(non_snake_case)]
 fn TIMER0_COMPA() {
-    interrupt::free(|cs| {})
+    interrupt::free(|cs| {
+        let counter_c = P_TICK_COUNTER.borrow(cs);
+        let increment_c = P_TICK_INCREMENT.borrow(cs);
+        let counter = counter_c.get() + increment_c.get();
+        counter_c.set(counter);
+        if let Some(tc0) = P_TICKER.tc0.borrow(cs).borrow().as_ref() {
+            schedule_precision_wakeup(P_QUEUE.borrow(cs).borrow_mut(), tc0, counter, increment_c)
+        }
+    })
 }